@@ -56,7 +56,7 @@ fn main() {
     let mut server = CDGramServer::new(
         server_pk,
         server_sk,
-        ::std::iter::once(client_pk.clone()),
+        ::std::iter::once((client_pk.clone(), None)),
         server_sock,
     );
     let recv_handle = ::async_std::task::spawn(async move { server.recv().await.unwrap() });
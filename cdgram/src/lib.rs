@@ -8,8 +8,9 @@ use ::sodiumoxide::crypto::{
     box_::{self, PublicKey, SecretKey},
     kx::{self, SessionKey},
 };
-use ::std::collections::{hash_map::Entry, HashMap, HashSet};
+use ::std::collections::{hash_map::Entry, HashMap};
 use ::std::pin::Pin;
+use ::std::time::SystemTime;
 use generator::{Generator, GeneratorState, Turnable};
 
 #[async_trait::async_trait]
@@ -89,23 +90,25 @@ pub struct CDGramServer<T> {
     _public: PublicKey,
     /// Our secret key
     secret: SecretKey,
-    authorized_keys: HashSet<PublicKey>,
+    /// Keys allowed to connect to us, with the time they stop being allowed, if they ever do
+    authorized_keys: RwLock<HashMap<PublicKey, Option<SystemTime>>>,
     socket: T,
-    auth_states: RwLock<HashMap<SocketAddr, AuthState>>,
+    /// Public key of the client, and the state of the session, for each client address
+    auth_states: RwLock<HashMap<SocketAddr, (PublicKey, AuthState)>>,
 }
 
 impl<T: 'static> CDGramServer<T> {
     pub fn new(
         public: PublicKey,
         secret: SecretKey,
-        authorized_keys: impl IntoIterator<Item = PublicKey>,
+        authorized_keys: impl IntoIterator<Item = (PublicKey, Option<SystemTime>)>,
         socket: T,
     ) -> Self {
         Self {
             _public: public,
             secret,
             socket,
-            authorized_keys: authorized_keys.into_iter().collect(),
+            authorized_keys: RwLock::new(authorized_keys.into_iter().collect()),
             auth_states: Default::default(),
         }
     }
@@ -169,6 +172,15 @@ impl<T: Socket> CDGramServer<T> {
             let mut auth_states = self.auth_states.write().await;
             let auth_state = auth_states.entry(addr);
 
+            let mut client_pk = None;
+            if let Entry::Occupied(entry) = &auth_state {
+                let (pubkey, _) = entry.get();
+                if !self.is_authorized(pubkey).await {
+                    // Ended by `close_expired` soon, until then just drop what it sends
+                    debug!("{} is using an expired key", addr);
+                    continue;
+                }
+            }
             if let Entry::Vacant(_) = auth_state {
                 info!("New connection from {}", addr);
                 if buf.len() < box_::PUBLICKEYBYTES {
@@ -176,16 +188,17 @@ impl<T: Socket> CDGramServer<T> {
                     continue;
                 }
                 let pubkey = box_::PublicKey::from_slice(&buf[0..box_::PUBLICKEYBYTES]).unwrap();
-                if !self.authorized_keys.contains(&pubkey) {
+                if !self.is_authorized(&pubkey).await {
                     // Unauthorized key, just drop the handshake packet
                     info!("{} sent us unauthorized pubkey", addr);
                     continue;
                 }
+                client_pk = Some(pubkey);
             }
-            let auth_state = auth_state.or_insert_with(|| {
+            let (_, auth_state) = auth_state.or_insert_with(|| {
                 let mut g = Box::pin(Generator::new(|g| handshake(our_sk, g)));
                 Pin::new(&mut g).start();
-                AuthState::Initiated(g)
+                (client_pk.unwrap(), AuthState::Initiated(g))
             });
             match auth_state {
                 AuthState::Initiated(g) => match Pin::new(g).turn(buf) {
@@ -224,9 +237,15 @@ impl<T: Socket> CDGramServer<T> {
             .with_context(|| "Failed to resolve address".to_owned())?;
         let send = {
             let auth_states = self.auth_states.read().await;
-            let auth_state = auth_states
+            let (pk, auth_state) = auth_states
                 .get(&addr)
                 .with_context(|| format!("Trying to send to unknown client {}", addr))?;
+            if !self.is_authorized(pk).await {
+                return Err(anyhow!(
+                    "Trying to send to client {} with an expired key",
+                    addr
+                ));
+            }
             match auth_state {
                 AuthState::Completed((_, tx)) => {
                     let nonce = aead::gen_nonce();
@@ -263,6 +282,58 @@ impl<T: Socket> CDGramServer<T> {
             )
             .is_some())
     }
+
+    /// The public key of the peer at `addr`, if we have a session with it
+    pub async fn peer_key(&self, addr: SocketAddr) -> Option<PublicKey> {
+        self.auth_states.read().await.get(&addr).map(|(pk, _)| *pk)
+    }
+
+    /// Whether `key` is allowed to connect to us right now
+    async fn is_authorized(&self, key: &PublicKey) -> bool {
+        match self.authorized_keys.read().await.get(key) {
+            Some(Some(expires)) => SystemTime::now() < *expires,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Allow `key` to connect to us, in addition to the keys that already are
+    pub async fn authorize_key(&self, key: PublicKey, expires: Option<SystemTime>) {
+        self.authorized_keys.write().await.insert(key, expires);
+    }
+
+    /// Replace the set of public keys that are allowed to connect to us, each with the time it
+    /// stops being allowed, if it ever does. Sessions with keys that are no longer allowed,
+    /// established or not, are closed. Returns the addresses of the closed sessions.
+    pub async fn set_authorized_keys(
+        &self,
+        authorized_keys: impl IntoIterator<Item = (PublicKey, Option<SystemTime>)>,
+    ) -> Vec<SocketAddr> {
+        *self.authorized_keys.write().await = authorized_keys.into_iter().collect();
+        self.close_expired().await
+    }
+
+    /// Close the sessions with keys that are no longer allowed, e.g. because they have expired
+    /// since the sessions were established. Has to be called every once in a while, until then
+    /// such sessions can't send or receive anything. Returns the addresses of the closed
+    /// sessions.
+    pub async fn close_expired(&self) -> Vec<SocketAddr> {
+        let mut auth_states = self.auth_states.write().await;
+        let mut closed = Vec::new();
+        for (addr, (pk, _)) in auth_states.iter() {
+            if !self.is_authorized(pk).await {
+                closed.push(*addr);
+            }
+        }
+        for addr in &closed {
+            info!(
+                "Closing connection to {}, its key is no longer authorized",
+                addr
+            );
+            auth_states.remove(addr);
+        }
+        closed
+    }
 }
 
 pub struct CDGramClient<T> {
@@ -1,31 +1,32 @@
 use super::Socket;
 use ::anyhow::{anyhow, Result};
+use ::async_std::channel::{Receiver, Sender};
 use ::async_std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
-use ::async_std::sync::{Receiver, Sender};
+use ::std::sync::Mutex;
 
 pub struct MockSocket {
     tx: Sender<(SocketAddr, SocketAddr, Vec<u8>)>,
     rx: Receiver<(SocketAddr, SocketAddr, Vec<u8>)>,
     local: SocketAddr,
-    remote: Option<SocketAddr>,
+    remote: Mutex<Option<SocketAddr>>,
 }
 
 impl MockSocket {
     pub fn new(addr1: SocketAddr, addr2: SocketAddr) -> (MockSocket, MockSocket) {
-        let (tx1, rx1) = ::async_std::sync::channel(1024);
-        let (tx2, rx2) = ::async_std::sync::channel(1024);
+        let (tx1, rx1) = ::async_std::channel::bounded(1024);
+        let (tx2, rx2) = ::async_std::channel::bounded(1024);
         (
             MockSocket {
                 tx: tx1,
                 rx: rx2,
                 local: addr1,
-                remote: None,
+                remote: Mutex::new(None),
             },
             MockSocket {
                 tx: tx2,
                 rx: rx1,
                 local: addr2,
-                remote: None,
+                remote: Mutex::new(None),
             },
         )
     }
@@ -33,18 +34,19 @@ impl MockSocket {
 
 #[async_trait::async_trait]
 impl Socket for MockSocket {
-    async fn send(&mut self, buf: &[u8]) -> Result<usize> {
-        if let Some(remote_addr) = self.remote {
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        let remote = *self.remote.lock().unwrap();
+        if let Some(remote_addr) = remote {
             self.tx
-                .send((self.local.clone(), remote_addr.clone(), buf.to_owned()))
-                .await;
+                .send((self.local, remote_addr, buf.to_owned()))
+                .await?;
             Ok(buf.len())
         } else {
             Err(anyhow!("Socket not connected"))
         }
     }
     async fn send_to(
-        &mut self,
+        &self,
         buf: &[u8],
         addr: impl ToSocketAddrs<Iter = impl Iterator<Item = SocketAddr> + Send + 'static>
             + Send
@@ -52,29 +54,27 @@ impl Socket for MockSocket {
             + 'static,
     ) -> Result<usize> {
         if let Some(remote) = addr.to_socket_addrs().await?.next() {
-            self.tx
-                .send((self.local.clone(), remote.clone(), buf.to_owned()))
-                .await;
+            self.tx.send((self.local, remote, buf.to_owned())).await?;
             Ok(buf.len())
         } else {
             Err(anyhow!("Failed to resolve remote"))
         }
     }
     async fn connect(
-        &mut self,
+        &self,
         addr: impl ToSocketAddrs<Iter = impl Iterator<Item = SocketAddr> + Send + 'static>
             + Send
             + Sync
             + 'static,
     ) -> Result<()> {
-        self.remote = addr.to_socket_addrs().await?.next();
+        *self.remote.lock().unwrap() = addr.to_socket_addrs().await?.next();
         Ok(())
     }
-    async fn recv(&mut self) -> Result<(SocketAddr, Vec<u8>)> {
+    async fn recv(&self) -> Result<(SocketAddr, Vec<u8>)> {
         loop {
             let (sender, receiver, payload) = self.rx.recv().await?;
-            if let Some(remote_addr) = self.remote.as_ref() {
-                if &sender != remote_addr {
+            if let Some(remote_addr) = *self.remote.lock().unwrap() {
+                if sender != remote_addr {
                     continue;
                 }
             }
@@ -103,11 +103,11 @@ fn test_connect() {
     let (server_pk, server_sk) = ::sodiumoxide::crypto::box_::gen_keypair();
     let (client_pk, client_sk) = ::sodiumoxide::crypto::box_::gen_keypair();
     let (server_addr, client_addr) = (random_addr(), random_addr());
-    let (server_sock, client_sock) = MockSocket::new(server_addr.clone(), client_addr.clone());
-    let mut server = CDGramServer::new(
+    let (server_sock, client_sock) = MockSocket::new(server_addr, client_addr);
+    let server = CDGramServer::new(
         server_pk,
         server_sk,
-        ::std::iter::once(client_pk.clone()),
+        ::std::iter::once((client_pk.clone(), None)),
         server_sock,
     );
     let mut client = CDGramClient::new(client_pk, client_sk, server_pk, client_sock);
@@ -128,3 +128,60 @@ fn test_connect() {
         assert_eq!(&pkt[..], &[5, 4, 3, 2, 1]);
     })
 }
+
+#[cfg(test)]
+#[test]
+fn test_expiry() {
+    use super::{CDGramClient, CDGramServer};
+    use ::std::sync::Arc;
+    use ::std::time::{Duration, SystemTime};
+    let (server_pk, server_sk) = ::sodiumoxide::crypto::box_::gen_keypair();
+    let (client_pk, client_sk) = ::sodiumoxide::crypto::box_::gen_keypair();
+    let expired = SystemTime::now() - Duration::from_secs(1);
+    let expires = SystemTime::now() + Duration::from_secs(60 * 60);
+
+    ::async_std::task::block_on(async move {
+        // A key that has already expired is refused at handshake, without a reply
+        let (server_addr, client_addr) = (random_addr(), random_addr());
+        let (server_sock, client_sock) = MockSocket::new(server_addr, client_addr);
+        let server = Arc::new(CDGramServer::new(
+            server_pk,
+            server_sk.clone(),
+            ::std::iter::once((client_pk, Some(expired))),
+            server_sock,
+        ));
+        let server2 = server.clone();
+        ::async_std::task::spawn(async move { server2.recv().await });
+        let mut client = CDGramClient::new(client_pk, client_sk.clone(), server_pk, client_sock);
+        let connect = client.connect(server_addr);
+        assert!(
+            ::async_std::future::timeout(Duration::from_millis(200), connect)
+                .await
+                .is_err()
+        );
+        assert!(server.peer_key(client_addr).await.is_none());
+
+        // An established session is closed once its key expires
+        let (server_addr, client_addr) = (random_addr(), random_addr());
+        let (server_sock, client_sock) = MockSocket::new(server_addr, client_addr);
+        let server = Arc::new(CDGramServer::new(
+            server_pk,
+            server_sk,
+            ::std::iter::once((client_pk, Some(expires))),
+            server_sock,
+        ));
+        let server2 = server.clone();
+        let recv_handle = ::async_std::task::spawn(async move { server2.recv().await });
+        let mut client = CDGramClient::new(client_pk, client_sk, server_pk, client_sock);
+        client.connect(server_addr).await.unwrap();
+        client.send(&[1, 2, 3]).await.unwrap();
+        let (addr, pkt) = recv_handle.await.unwrap();
+        assert_eq!(addr, client_addr);
+        assert_eq!(&pkt[..], &[1, 2, 3]);
+        assert!(server.close_expired().await.is_empty());
+
+        server.authorize_key(client_pk, Some(expired)).await;
+        assert_eq!(server.close_expired().await, vec![client_addr]);
+        assert!(server.peer_key(client_addr).await.is_none());
+    })
+}
@@ -0,0 +1,179 @@
+use ::serde_derive::{Deserialize, Serialize};
+use ::std::net::{IpAddr, SocketAddr};
+use ::std::str::FromStr;
+
+/// Address of a peer, as a host name (or a literal IP) plus a port.
+///
+/// The host is kept as written, and only resolved when we actually need to talk to the peer, so
+/// peers whose address changes (e.g. through DHCP) can still be found by name. mDNS `.local`
+/// names work as long as the system resolver supports them (e.g. through nss-mdns).
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PeerAddr {
+    host: String,
+    port: u16,
+}
+
+/// Something that can turn a host name into IP addresses.
+pub trait Resolver {
+    fn resolve(&self, host: &str, port: u16) -> ::std::io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves host names with the system resolver (i.e. getaddrinfo).
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> ::std::io::Result<Vec<SocketAddr>> {
+        use ::std::net::ToSocketAddrs;
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+impl PeerAddr {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+    pub fn set_port(&mut self, port: u16) {
+        self.port = port;
+    }
+    /// Returns the IP address if the host is a literal IP address
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+    /// Resolve the address with `resolver`. Literal IP addresses are returned as is.
+    pub fn resolve_with(&self, resolver: &impl Resolver) -> ::std::io::Result<Vec<SocketAddr>> {
+        if let Some(ip) = self.ip() {
+            return Ok(vec![SocketAddr::new(ip, self.port)]);
+        }
+        let addrs = resolver.resolve(&self.host, self.port)?;
+        if addrs.is_empty() {
+            Err(::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("{} doesn't resolve to any address", self.host),
+            ))
+        } else {
+            Ok(addrs)
+        }
+    }
+    /// Resolve the address with the system resolver.
+    pub fn resolve(&self) -> ::std::io::Result<Vec<SocketAddr>> {
+        self.resolve_with(&SystemResolver)
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl ::std::fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl FromStr for PeerAddr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            // [v6 address]:port
+            let end = rest
+                .find(']')
+                .ok_or_else(|| format!("Unterminated '[' in address {}", s))?;
+            let port = rest[end + 1..]
+                .strip_prefix(':')
+                .ok_or_else(|| format!("Missing port in address {}", s))?;
+            (&rest[..end], port)
+        } else {
+            let sep = s
+                .rfind(':')
+                .ok_or_else(|| format!("Missing port in address {}", s))?;
+            (&s[..sep], &s[sep + 1..])
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in address {}", s));
+        }
+        let port = port
+            .parse()
+            .map_err(|e| format!("Invalid port in address {}: {}", s, e))?;
+        Ok(Self::new(host, port))
+    }
+}
+
+impl ::std::convert::TryFrom<String> for PeerAddr {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PeerAddr> for String {
+    fn from(addr: PeerAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerAddr, Resolver};
+    use ::std::net::SocketAddr;
+
+    /// Resolves every host to whatever address is currently stored in it
+    struct StubResolver(::std::cell::RefCell<Vec<SocketAddr>>);
+    impl Resolver for StubResolver {
+        fn resolve(&self, _host: &str, port: u16) -> ::std::io::Result<Vec<SocketAddr>> {
+            Ok(self
+                .0
+                .borrow()
+                .iter()
+                .map(|a| SocketAddr::new(a.ip(), port))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let a: PeerAddr = "desktop.local:3241".parse().unwrap();
+        assert_eq!(a.host(), "desktop.local");
+        assert_eq!(a.port(), 3241);
+        let a: PeerAddr = "[fe80::1]:3241".parse().unwrap();
+        assert_eq!(a.host(), "fe80::1");
+        assert_eq!(a.to_string(), "[fe80::1]:3241");
+        let a: PeerAddr = "10.0.0.1:1".parse().unwrap();
+        assert_eq!(a.ip(), Some("10.0.0.1".parse().unwrap()));
+        assert!("desktop".parse::<PeerAddr>().is_err());
+        assert!(":3241".parse::<PeerAddr>().is_err());
+    }
+
+    #[test]
+    fn test_re_resolve() {
+        let resolver = StubResolver(vec!["10.0.0.1:0".parse().unwrap()].into());
+        let a: PeerAddr = "desktop.local:3241".parse().unwrap();
+        assert_eq!(
+            a.resolve_with(&resolver).unwrap(),
+            vec!["10.0.0.1:3241".parse::<SocketAddr>().unwrap()]
+        );
+        // The address changed, e.g. a new DHCP lease
+        *resolver.0.borrow_mut() = vec!["10.0.0.7:0".parse().unwrap()];
+        assert_eq!(
+            a.resolve_with(&resolver).unwrap(),
+            vec!["10.0.0.7:3241".parse::<SocketAddr>().unwrap()]
+        );
+        resolver.0.borrow_mut().clear();
+        assert!(a.resolve_with(&resolver).is_err());
+    }
+}
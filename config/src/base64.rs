@@ -13,7 +13,7 @@ where
     D: Deserializer<'de>,
 {
     use std::io::Write;
-    let s = <&str>::deserialize(deserializer)?;
+    let s = String::deserialize(deserializer)?;
     let mut ret: Buf = Default::default();
     let v = base64::decode_config(s, base64::URL_SAFE_NO_PAD).map_err(de::Error::custom)?;
     if (&mut ret).as_mut().write(&v).map_err(de::Error::custom)? != v.len() {
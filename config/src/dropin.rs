@@ -0,0 +1,142 @@
+//! Merging of the main config file with the drop-in files next to it.
+//!
+//! Drop-ins are the `*.toml` files in the directory named after the main config file, with the
+//! extension replaced by `.d` (e.g. `/etc/entangle.d` for `/etc/entangle.conf`). They are applied
+//! in the lexical order of their file names, after the main config file. `peers` from every file
+//! are appended to each other, while any other setting in a later file replaces the same setting
+//! from the earlier ones.
+
+use ::anyhow::{Context, Result};
+use ::std::collections::HashMap;
+use ::std::path::{Path, PathBuf};
+use ::toml::value::{Table, Value};
+
+pub(crate) const PEERS: &str = "peers";
+
+/// Result of merging the config files
+pub(crate) struct Merged {
+    /// The effective config
+    pub(crate) table: Table,
+    /// The drop-in each peer came from, `None` if the peer is from the main config file.
+    pub(crate) peer_origins: Vec<Option<PathBuf>>,
+    /// Settings in the main config file that are overridden by drop-ins, and their values in
+    /// the main config file (`None` if they are not set there)
+    pub(crate) overridden: HashMap<String, Option<Value>>,
+    /// Drop-in files that have been merged
+    pub(crate) files: Vec<PathBuf>,
+}
+
+/// Directory containing the drop-ins for the config file at `path`
+pub fn dropin_dir(path: &Path) -> PathBuf {
+    path.with_extension("d")
+}
+
+fn read_table(path: &Path) -> Result<Table> {
+    let s = ::std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    ::toml::from_str(&s).with_context(|| format!("Failed to parse config file {}", path.display()))
+}
+
+fn peer_count(table: &Table, path: &Path) -> Result<usize> {
+    match table.get(PEERS) {
+        None => Ok(0),
+        Some(Value::Array(peers)) => Ok(peers.len()),
+        Some(_) => Err(::anyhow::anyhow!(
+            "`{}` in {} is not an array",
+            PEERS,
+            path.display()
+        )),
+    }
+}
+
+/// Read the config file at `path` and merge its drop-ins into it.
+pub(crate) fn read_merged(path: &Path) -> Result<Merged> {
+    let mut table = read_table(path)?;
+    let mut merged = Merged {
+        peer_origins: vec![None; peer_count(&table, path)?],
+        table: Table::new(),
+        overridden: HashMap::new(),
+        files: Vec::new(),
+    };
+
+    let dir = dropin_dir(path);
+    let mut files = match ::std::fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to read {}", dir.display()))?,
+        Err(e) if e.kind() == ::std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    files.retain(|f| f.extension() == Some("toml".as_ref()) && f.is_file());
+    files.sort();
+
+    for file in files {
+        let dropin = read_table(&file)?;
+        let n = peer_count(&dropin, &file)?;
+        for (key, value) in dropin {
+            if key == PEERS {
+                let peers = table
+                    .entry(PEERS)
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                if let (Value::Array(peers), Value::Array(value)) = (peers, value) {
+                    peers.extend(value);
+                }
+            } else {
+                let old = table.insert(key.clone(), value);
+                merged.overridden.entry(key).or_insert(old);
+            }
+        }
+        let len = merged.peer_origins.len();
+        merged.peer_origins.resize(len + n, Some(file.clone()));
+        merged.files.push(file);
+    }
+    merged.table = table;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_merge_order() {
+        let dir = ::std::env::temp_dir().join(format!("entangle-dropin-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(dir.join("entangle.d")).unwrap();
+        let main = dir.join("entangle.conf");
+        ::std::fs::write(&main, "a = 1\nb = 1\n[[peers]]\nname = \"main\"\n").unwrap();
+        ::std::fs::write(
+            dir.join("entangle.d/20-b.toml"),
+            "b = 3\n[[peers]]\nname = \"20\"\n",
+        )
+        .unwrap();
+        ::std::fs::write(
+            dir.join("entangle.d/10-a.toml"),
+            "b = 2\nc = 2\n[[peers]]\nname = \"10\"\n",
+        )
+        .unwrap();
+        ::std::fs::write(dir.join("entangle.d/ignored.conf"), "b = 4\n").unwrap();
+
+        let merged = super::read_merged(&main).unwrap();
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        let names: Vec<_> = merged.table["peers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["main", "10", "20"]);
+        assert_eq!(merged.table["a"].as_integer(), Some(1));
+        assert_eq!(merged.table["b"].as_integer(), Some(3));
+        assert_eq!(merged.table["c"].as_integer(), Some(2));
+        assert_eq!(merged.overridden["b"], Some(::toml::Value::Integer(1)));
+        assert_eq!(merged.overridden["c"], None);
+        assert_eq!(
+            merged.peer_origins,
+            vec![
+                None,
+                Some(dir.join("entangle.d/10-a.toml")),
+                Some(dir.join("entangle.d/20-b.toml"))
+            ]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{Peer, PeerAddr};
+use ::anyhow::{anyhow, Context, Result};
+use ::sodiumoxide::crypto::box_::PublicKey;
+use ::std::str::FromStr;
+
+const PREFIX: &str = "entangle:";
+
+/// Everything needed to add someone as a peer, in a compact form that can be copied around:
+/// `entangle:<base64 public key>[@<address>][#<name>]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Identity {
+    pub public: PublicKey,
+    pub addr: Option<PeerAddr>,
+    pub name: Option<String>,
+}
+
+impl From<Identity> for Peer {
+    fn from(identity: Identity) -> Self {
+        let mut peer = Peer::new(identity.addr, identity.public);
+        peer.name = identity.name;
+        peer
+    }
+}
+
+impl ::std::fmt::Display for Identity {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            PREFIX,
+            ::base64::encode_config(self.public.as_ref(), ::base64::URL_SAFE_NO_PAD)
+        )?;
+        if let Some(addr) = &self.addr {
+            write!(f, "@{}", addr)?;
+        }
+        if let Some(name) = &self.name {
+            write!(f, "#{}", name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = ::anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s
+            .trim()
+            .strip_prefix(PREFIX)
+            .with_context(|| format!("Identity doesn't start with {}", PREFIX))?;
+        let (s, name) = match s.find('#') {
+            Some(i) => (&s[..i], Some(s[i + 1..].to_owned())),
+            None => (s, None),
+        };
+        let (key, addr) = match s.find('@') {
+            Some(i) => (
+                &s[..i],
+                Some(s[i + 1..].parse().map_err(|e| anyhow!("{}", e))?),
+            ),
+            None => (s, None),
+        };
+        let key = ::base64::decode_config(key, ::base64::URL_SAFE_NO_PAD)
+            .context("Malformed public key in identity")?;
+        let public = PublicKey::from_slice(&key)
+            .with_context(|| "Public key in identity has the wrong length".to_owned())?;
+        Ok(Self {
+            public,
+            addr,
+            name: name.filter(|n| !n.is_empty()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Identity;
+
+    #[test]
+    fn test_round_trip() {
+        let (public, _) = ::sodiumoxide::crypto::box_::gen_keypair();
+        let identity = Identity {
+            public,
+            addr: Some("[fe80::1]:3241".parse().unwrap()),
+            name: Some("living room #2".to_owned()),
+        };
+        let s = identity.to_string();
+        assert_eq!(s.parse::<Identity>().unwrap(), identity);
+        let bare = Identity {
+            public,
+            addr: None,
+            name: None,
+        };
+        assert_eq!(bare.to_string().parse::<Identity>().unwrap(), bare);
+        assert!(s
+            .trim_start_matches("entangle:")
+            .parse::<Identity>()
+            .is_err());
+    }
+}
@@ -1,26 +1,209 @@
+use ::anyhow::{anyhow, Context, Result};
 use ::serde_derive::{Deserialize, Serialize};
 use ::sodiumoxide::crypto::box_::{PublicKey, SecretKey, PUBLICKEYBYTES, SECRETKEYBYTES};
+use ::std::collections::HashMap;
 use ::std::mem::MaybeUninit;
+use ::std::path::{Path, PathBuf};
+use ::std::time::Duration;
+mod addr;
 mod base64;
+mod dropin;
+mod identity;
+mod secret;
+
+pub use addr::{PeerAddr, Resolver, SystemResolver};
+pub use dropin::dropin_dir;
+pub use identity::Identity;
+pub use secret::{passphrase_from_env_or_stdin, SecretStorage, StagedKey};
+
+/// Port the server daemon listens on, unless the config file says otherwise
+pub const DEFAULT_PORT: u16 = 3241;
+/// Version of the protocol the daemons speak, told to the other side when pairing
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Longest time an old key is accepted for after a key rotation
+pub const MAX_GRACE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
 
 #[derive(Serialize, Deserialize)]
 pub struct Peer {
-    pub addr: Option<::std::net::SocketAddr>,
+    /// A friendly name for the peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub addr: Option<PeerAddr>,
     #[serde(with = "base64")]
     public: [u8; PUBLICKEYBYTES],
+    /// Protocol version the peer spoke when it was paired
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<u32>,
+    /// The key this peer used before it rotated to `public`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous: Option<PreviousKey>,
+    /// For guests: seconds since the unix epoch after which the peer can no longer connect
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// For guests: which of the forwarded input devices the peer can use. All of them if not
+    /// set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub devices: Option<DevicePolicy>,
+    /// The drop-in file this peer is from, `None` if it's from the main config file
+    #[serde(skip)]
+    origin: Option<PathBuf>,
+}
+
+/// A public key that is still accepted for a while after its peer has rotated away from it
+#[derive(Serialize, Deserialize, Clone)]
+struct PreviousKey {
+    #[serde(with = "base64")]
+    public: [u8; PUBLICKEYBYTES],
+    /// Seconds since the unix epoch after which the key is no longer accepted
+    expires: u64,
+}
+
+/// Where the config file is by default: `/etc/entangle.conf` for root, and
+/// `$XDG_CONFIG_HOME/entangle/entangle.conf` (usually `~/.config/entangle/entangle.conf`) for
+/// everyone else.
+pub fn default_path() -> PathBuf {
+    const SYSTEM_PATH: &str = "/etc/entangle.conf";
+    if unsafe { ::libc::geteuid() } == 0 {
+        return SYSTEM_PATH.into();
+    }
+    let config_home = ::std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| ::std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    match config_home {
+        Some(dir) => dir.join("entangle").join("entangle.conf"),
+        None => SYSTEM_PATH.into(),
+    }
+}
+
+/// Host name of this machine, used as the default friendly name
+pub fn host_name() -> Option<String> {
+    let name = ::std::fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(name.trim().to_owned()).filter(|n| !n.is_empty())
+}
+
+/// Seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Short fingerprint of `pk`: the start of its SHA-256 hash, in hex
+pub fn fingerprint(pk: &PublicKey) -> String {
+    let hash = ::sodiumoxide::crypto::hash::sha256::hash(pk.as_ref());
+    hash.as_ref()[..8]
+        .chunks(2)
+        .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn key_bytes(pk: &PublicKey) -> [u8; PUBLICKEYBYTES] {
+    let mut public = [0; PUBLICKEYBYTES];
+    public.copy_from_slice(pk.as_ref());
+    public
 }
 
 impl Peer {
     pub fn public(&self) -> PublicKey {
         PublicKey::from_slice(&self.public[..]).unwrap()
     }
-    pub fn new(addr: Option<::std::net::SocketAddr>, pk: PublicKey) -> Self {
+    /// All the keys this peer is allowed to use: its current key, and, during the grace period
+    /// after a key rotation, its previous key.
+    pub fn public_keys(&self) -> impl Iterator<Item = PublicKey> + '_ {
+        let now = unix_time();
+        ::std::iter::once(self.public()).chain(
+            self.previous
+                .iter()
+                .filter(move |p| p.expires > now)
+                .map(|p| PublicKey::from_slice(&p.public[..]).unwrap()),
+        )
+    }
+    /// Every key this peer has been allowed to use, with the time in seconds since the unix
+    /// epoch after which it no longer is, if there is one. Some of them might have expired
+    /// already.
+    pub fn authorized_keys(&self) -> impl Iterator<Item = (PublicKey, Option<u64>)> + '_ {
+        let expires = self.expires;
+        ::std::iter::once((self.public(), expires)).chain(self.previous.iter().map(move |p| {
+            let key = PublicKey::from_slice(&p.public[..]).unwrap();
+            (key, Some(expires.map_or(p.expires, |e| e.min(p.expires))))
+        }))
+    }
+    /// Whether this is a guest whose access has expired
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_time())
+    }
+    /// Short fingerprint of the current key, for humans to compare
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public())
+    }
+    /// Replace the key of this peer with `pk`, and keep accepting the current key for `grace`,
+    /// at most `MAX_GRACE`.
+    pub fn rotate_key(&mut self, pk: PublicKey, grace: Duration) {
+        self.previous = Some(PreviousKey {
+            public: self.public,
+            expires: unix_time().saturating_add(grace.min(MAX_GRACE).as_secs()),
+        });
+        self.public = key_bytes(&pk);
+    }
+    /// The drop-in file this peer is defined in, `None` if it's in the main config file
+    pub fn origin(&self) -> Option<&Path> {
+        self.origin.as_deref()
+    }
+    pub fn new(addr: Option<PeerAddr>, pk: PublicKey) -> Self {
         let mut public = MaybeUninit::<[u8; PUBLICKEYBYTES]>::uninit();
         let public = unsafe {
             (*public.as_mut_ptr()).copy_from_slice(pk.as_ref());
             public.assume_init()
         };
-        Self { addr, public }
+        Self {
+            name: None,
+            addr,
+            public,
+            protocol: None,
+            previous: None,
+            expires: None,
+            devices: None,
+            origin: None,
+        }
+    }
+}
+
+/// Which input devices are forwarded, by device name
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct DevicePolicy {
+    /// If not empty, only devices with these names are forwarded
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Devices with these names are never forwarded
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl DevicePolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+    /// Whether the device named `name` should be forwarded
+    pub fn allows(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|n| n == name))
+            && !self.deny.iter().any(|n| n == name)
+    }
+}
+
+/// A time during which the server daemon lets new clients pair with it. The code clients have to
+/// pair with is kept out of the config file, by the daemon.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PairingWindow {
+    /// Seconds since the unix epoch after which pairing is no longer allowed
+    pub until: u64,
+}
+
+impl PairingWindow {
+    pub fn is_open(&self) -> bool {
+        unix_time() < self.until
     }
 }
 
@@ -28,17 +211,270 @@ impl Peer {
 pub struct Config {
     #[serde(with = "base64")]
     public: [u8; PUBLICKEYBYTES],
-    #[serde(with = "base64")]
-    secret: [u8; SECRETKEYBYTES],
+    secret: SecretStorage,
+    /// Friendly name of this machine, told to peers when pairing. Defaults to the host name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Port the server daemon listens on, `DEFAULT_PORT` if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// The secret key, loaded from wherever `secret` says it's stored
+    #[serde(skip)]
+    secret_key: Option<SecretKey>,
+    /// Which input devices the server forwards to its clients
+    #[serde(default, skip_serializing_if = "DevicePolicy::is_empty")]
+    pub devices: DevicePolicy,
+    /// Seconds since the unix epoch until which our peers keep accepting the key we had before
+    /// the last `rotate`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rotated_until: Option<u64>,
+    /// Whether the server daemon currently lets new clients pair with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairing: Option<PairingWindow>,
+    /// Peers from the main config file, followed by peers from the drop-ins
     pub peers: Vec<Peer>,
+    /// Where the config was read from
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Drop-in files merged into this config
+    #[serde(skip)]
+    dropins: Vec<PathBuf>,
+    /// Settings overridden by drop-ins, with their values from the main config file
+    #[serde(skip)]
+    overridden: HashMap<String, Option<::toml::Value>>,
+}
+
+/// Hidden file next to the config file at `path`, named after it with `suffix` appended, for
+/// state that doesn't belong in the config file
+pub fn state_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = ::std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Write `contents` to `path`, making sure the file is only accessible by its owner. The file is
+/// replaced atomically, so readers never see it half written.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    use ::std::io::Write;
+    use ::std::os::unix::fs::OpenOptionsExt;
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?;
+    let mut tmp_name = ::std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".tmp{}", ::std::process::id()));
+    let tmp = path.with_file_name(tmp_name);
+    // Left over from a crash, maybe
+    let _ = ::std::fs::remove_file(&tmp);
+    let result = (|| {
+        let mut f = ::std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("Failed to open {}", tmp.display()))?;
+        f.write_all(contents)?;
+        f.sync_all()?;
+        ::std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to replace {}", path.display()))
+    })();
+    if result.is_err() {
+        let _ = ::std::fs::remove_file(&tmp);
+    }
+    result
 }
 
 impl Config {
     pub fn public(&self) -> PublicKey {
         PublicKey::from_slice(&self.public[..]).unwrap()
     }
+    /// Our secret key, regardless of where it is stored.
     pub fn secret(&self) -> SecretKey {
-        SecretKey::from_slice(&self.secret[..]).unwrap()
+        self.secret_key
+            .clone()
+            .expect("Config wasn't created with Config::load or Config::generate")
+    }
+    /// Friendly name of this machine
+    pub fn name(&self) -> Option<String> {
+        self.name.clone().or_else(host_name)
+    }
+    /// Port the server daemon listens on
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+    /// Where the secret key is stored
+    pub fn secret_storage(&self) -> &SecretStorage {
+        &self.secret
+    }
+    /// Index of the peer `query` refers to, by name, host name or key fingerprint (prefix)
+    pub fn find_peer(&self, query: &str) -> Result<usize> {
+        let fingerprint = query.replace(':', "").to_lowercase();
+        let matches: Vec<_> = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                p.name.as_deref() == Some(query)
+                    || p.addr.as_ref().map(PeerAddr::host) == Some(query)
+                    || (!fingerprint.is_empty()
+                        && p.fingerprint().replace(':', "").starts_with(&fingerprint))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        match matches[..] {
+            [i] => Ok(i),
+            [] => Err(anyhow!("No peer matches {}", query)),
+            _ => Err(anyhow!("{} matches more than one peer", query)),
+        }
+    }
+    /// Read the config file at `path`, and merge the drop-ins for it (see `dropin_dir`) into it.
+    ///
+    /// Drop-ins are merged in the lexical order of their file names. Peers from them are
+    /// appended, and any other setting overrides the one from the main config file and earlier
+    /// drop-ins. The secret key is not loaded, call `load_secret` for that.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let merged = dropin::read_merged(path)?;
+        let mut cfg: Self = ::toml::Value::Table(merged.table)
+            .try_into()
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        for (peer, origin) in cfg.peers.iter_mut().zip(merged.peer_origins) {
+            peer.origin = origin;
+        }
+        cfg.path = Some(path.to_owned());
+        cfg.dropins = merged.files;
+        cfg.overridden = merged.overridden;
+        Ok(cfg)
+    }
+    /// Load the secret key from wherever it is stored. `passphrase` is called if the secret key
+    /// is encrypted.
+    pub fn load_secret(&mut self, passphrase: impl FnOnce() -> Result<String>) -> Result<()> {
+        self.secret_key = Some(self.secret.load(self.base_dir(), passphrase)?);
+        Ok(())
+    }
+    /// Directory relative key file paths are relative to, i.e. where the main config file is
+    fn base_dir(&self) -> &Path {
+        self.path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new("."))
+    }
+    /// Replace our key pair with a newly generated one, which our peers are told to accept the
+    /// current key for `grace` longer, at most `MAX_GRACE`. `passphrase` is only needed if the
+    /// secret key is stored encrypted. The config has to be saved afterwards, and then the
+    /// returned key committed to wherever the current secret key is stored. Until then the
+    /// current secret key is kept.
+    pub fn rotate(&mut self, passphrase: Option<&str>, grace: Duration) -> Result<StagedKey> {
+        let (pk, sk) = ::sodiumoxide::crypto::box_::gen_keypair();
+        let base = self.base_dir().to_owned();
+        let staged = self.secret.stage(&base, &sk, passphrase)?;
+        self.public = key_bytes(&pk);
+        self.secret_key = Some(sk);
+        self.rotated_until = Some(unix_time().saturating_add(grace.min(MAX_GRACE).as_secs()));
+        Ok(staged)
+    }
+    /// How much longer our peers keep accepting the key we had before the last `rotate`
+    pub fn rotation_grace(&self) -> Duration {
+        let until = self.rotated_until.unwrap_or(0);
+        Duration::from_secs(until.saturating_sub(unix_time()))
+    }
+    /// Read the config file at `path` together with its drop-ins, and load the secret key it
+    /// refers to. `passphrase` is called if the secret key is encrypted.
+    pub fn load(
+        path: impl AsRef<Path>,
+        passphrase: impl FnOnce() -> Result<String>,
+    ) -> Result<Self> {
+        let mut cfg = Self::read(path)?;
+        cfg.load_secret(passphrase)?;
+        Ok(cfg)
+    }
+    /// Read the config again from the files it was read from. The secret key that has already
+    /// been loaded is reused if where it's stored hasn't changed, otherwise it's loaded again.
+    pub fn reload(&self, passphrase: impl FnOnce() -> Result<String>) -> Result<Self> {
+        let path = self
+            .path
+            .as_ref()
+            .with_context(|| "Config wasn't read from a file".to_owned())?;
+        let mut cfg = Self::read(path)?;
+        if cfg.secret == self.secret && cfg.public == self.public {
+            cfg.secret_key = self.secret_key.clone();
+        } else {
+            cfg.load_secret(passphrase)?;
+        }
+        Ok(cfg)
+    }
+    /// The main config file this config was read from
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+    /// Drop-in files that were merged into this config
+    pub fn dropins(&self) -> &[PathBuf] {
+        &self.dropins
+    }
+    /// Save the config to `path`. The secret key is only written if it is stored inline.
+    ///
+    /// Only what belongs in the main config file is written: peers that came from drop-ins are
+    /// left out, and settings overridden by drop-ins keep their values from the main config file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut table = match ::toml::Value::try_from(self)? {
+            ::toml::Value::Table(table) => table,
+            _ => unreachable!(),
+        };
+        let peers: Vec<_> = self.peers.iter().filter(|p| p.origin.is_none()).collect();
+        table.insert(dropin::PEERS.to_owned(), ::toml::Value::try_from(peers)?);
+        for (key, value) in &self.overridden {
+            if let Some(value) = value {
+                table.insert(key.clone(), value.clone());
+            } else {
+                table.remove(key);
+            }
+        }
+        write_private(
+            path.as_ref(),
+            ::toml::ser::to_string(&::toml::Value::Table(table))?.as_bytes(),
+        )
+    }
+    /// The effective configuration, after merging the drop-ins, in TOML. An inline secret key is
+    /// left out.
+    pub fn effective(&self) -> Result<String> {
+        let mut table = match ::toml::Value::try_from(self)? {
+            ::toml::Value::Table(table) => table,
+            _ => unreachable!(),
+        };
+        if let SecretStorage::Inline(_) = self.secret {
+            table.remove("secret");
+        }
+        let mut ret = String::new();
+        for file in self.path.iter().chain(self.dropins.iter()) {
+            ret += &format!("# {}\n", file.display());
+        }
+        ret += &::toml::ser::to_string(&::toml::Value::Table(table))?;
+        Ok(ret)
+    }
+    /// Move the secret key into the key file at `path`, encrypted with `passphrase` if it's
+    /// given. A relative `path` is relative to the current directory, and is stored as an absolute
+    /// path, since paths in the config file are relative to its directory. The config has to be
+    /// saved afterwards for the change to take effect.
+    pub fn move_secret(&mut self, path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<()> {
+        let path = ::std::env::current_dir()
+            .context("Failed to find the current directory")?
+            .join(path);
+        let path = path.as_path();
+        let sk = self.secret();
+        if let Some(passphrase) = passphrase {
+            write_private(path, secret::encrypt_key(&sk, passphrase)?.as_bytes())?;
+            self.secret = SecretStorage::Encrypted {
+                encrypted_file: path.to_owned(),
+            };
+        } else {
+            let encoded = ::base64::encode_config(sk.as_ref(), ::base64::URL_SAFE_NO_PAD);
+            write_private(path, encoded.as_bytes())?;
+            self.secret = SecretStorage::File {
+                file: path.to_owned(),
+            };
+        }
+        Ok(())
     }
     pub fn generate() -> Self {
         let (pk, sk) = ::sodiumoxide::crypto::box_::gen_keypair();
@@ -54,8 +490,17 @@ impl Config {
         };
         Self {
             public,
-            secret,
+            secret: SecretStorage::Inline(secret),
+            secret_key: Some(sk),
+            name: None,
+            port: None,
+            devices: DevicePolicy::default(),
+            rotated_until: None,
+            pairing: None,
             peers: Vec::new(),
+            path: None,
+            dropins: Vec::new(),
+            overridden: HashMap::new(),
         }
     }
 }
@@ -0,0 +1,239 @@
+use ::anyhow::{anyhow, Context, Result};
+use ::serde_derive::{Deserialize, Serialize};
+use ::sodiumoxide::crypto::box_::{SecretKey, SECRETKEYBYTES};
+use ::sodiumoxide::crypto::pwhash::argon2id13 as pwhash;
+use ::sodiumoxide::crypto::secretbox;
+use ::std::path::{Path, PathBuf};
+
+/// Where the secret key is stored.
+///
+/// In the config file this is either the base64 encoded key itself, or a table with exactly one
+/// of `file`, `credential` or `encrypted_file`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum SecretStorage {
+    /// Stored inline in the config file
+    Inline(#[serde(with = "super::base64")] [u8; SECRETKEYBYTES]),
+    /// Stored in a separate file, either raw or base64 encoded
+    File { file: PathBuf },
+    /// Stored as a systemd credential with this name, see `LoadCredential=` in systemd.exec(5)
+    Credential { credential: String },
+    /// Stored in a separate file, encrypted with a passphrase
+    Encrypted { encrypted_file: PathBuf },
+}
+
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+fn decode_key(data: &[u8]) -> Result<SecretKey> {
+    if data.len() == SECRETKEYBYTES {
+        return Ok(SecretKey::from_slice(data).unwrap());
+    }
+    let text = ::std::str::from_utf8(data).context("Key file is neither raw nor base64")?;
+    let data = ::base64::decode_config(text.trim(), ::base64::URL_SAFE_NO_PAD)?;
+    SecretKey::from_slice(&data).with_context(|| "Secret key has the wrong length".to_owned())
+}
+
+fn derive_key(passphrase: &str, salt: &pwhash::Salt) -> Result<secretbox::Key> {
+    let mut key = secretbox::Key([0; secretbox::KEYBYTES]);
+    pwhash::derive_key(
+        &mut key.0,
+        passphrase.as_bytes(),
+        salt,
+        pwhash::OPSLIMIT_INTERACTIVE,
+        pwhash::MEMLIMIT_INTERACTIVE,
+    )
+    .map_err(|()| anyhow!("Failed to derive key from passphrase"))?;
+    Ok(key)
+}
+
+/// Encrypt `sk` with `passphrase`. The result is base64 encoded salt + nonce + ciphertext.
+pub(crate) fn encrypt_key(sk: &SecretKey, passphrase: &str) -> Result<String> {
+    let salt = pwhash::gen_salt();
+    let nonce = secretbox::gen_nonce();
+    let key = derive_key(passphrase, &salt)?;
+    let mut data = salt.as_ref().to_vec();
+    data.extend(nonce.as_ref());
+    data.extend(secretbox::seal(sk.as_ref(), &nonce, &key));
+    Ok(::base64::encode_config(&data, ::base64::URL_SAFE_NO_PAD))
+}
+
+fn decrypt_key(data: &[u8], passphrase: &str) -> Result<SecretKey> {
+    let text = ::std::str::from_utf8(data).context("Malformed encrypted key file")?;
+    let data = ::base64::decode_config(text.trim(), ::base64::URL_SAFE_NO_PAD)
+        .context("Malformed encrypted key file")?;
+    if data.len()
+        != pwhash::SALTBYTES + secretbox::NONCEBYTES + SECRETKEYBYTES + secretbox::MACBYTES
+    {
+        return Err(anyhow!("Malformed encrypted key file"));
+    }
+    let (salt, data) = data.split_at(pwhash::SALTBYTES);
+    let (nonce, data) = data.split_at(secretbox::NONCEBYTES);
+    let key = derive_key(passphrase, &pwhash::Salt::from_slice(salt).unwrap())?;
+    let sk = secretbox::open(data, &secretbox::Nonce::from_slice(nonce).unwrap(), &key)
+        .map_err(|()| anyhow!("Wrong passphrase"))?;
+    Ok(SecretKey::from_slice(&sk).unwrap())
+}
+
+impl SecretStorage {
+    /// Load the secret key. `base` is the directory relative paths are resolved against, and
+    /// `passphrase` is only called if the key is encrypted.
+    pub fn load(
+        &self,
+        base: &Path,
+        passphrase: impl FnOnce() -> Result<String>,
+    ) -> Result<SecretKey> {
+        match self {
+            Self::Inline(sk) => Ok(SecretKey::from_slice(&sk[..]).unwrap()),
+            Self::File { file } => {
+                let file = base.join(file);
+                let data = ::std::fs::read(&file)
+                    .with_context(|| format!("Failed to read key file {}", file.display()))?;
+                decode_key(&data)
+                    .with_context(|| format!("Failed to load key from {}", file.display()))
+            }
+            Self::Credential { credential } => {
+                let dir = ::std::env::var_os(CREDENTIALS_DIRECTORY).with_context(|| {
+                    format!(
+                        "Secret key is stored in credential {}, but {} is not set",
+                        credential, CREDENTIALS_DIRECTORY
+                    )
+                })?;
+                let file = Path::new(&dir).join(credential);
+                let data = ::std::fs::read(&file)
+                    .with_context(|| format!("Failed to read credential {}", file.display()))?;
+                decode_key(&data)
+                    .with_context(|| format!("Failed to load key from {}", file.display()))
+            }
+            Self::Encrypted { encrypted_file } => {
+                let file = base.join(encrypted_file);
+                let data = ::std::fs::read(&file)
+                    .with_context(|| format!("Failed to read key file {}", file.display()))?;
+                decrypt_key(&data, &passphrase()?)
+                    .with_context(|| format!("Failed to decrypt key from {}", file.display()))
+            }
+        }
+    }
+}
+
+/// A new secret key written next to the key file it is going to replace, see
+/// `SecretStorage::stage`. Removed again if dropped before being committed.
+pub struct StagedKey {
+    /// The staged key, and the key file it replaces. `None` if there is nothing left to do.
+    files: Option<(PathBuf, PathBuf)>,
+}
+
+impl StagedKey {
+    /// Replace the key file with the staged key
+    pub fn commit(mut self) -> Result<()> {
+        if let Some((staged, file)) = self.files.take() {
+            ::std::fs::rename(&staged, &file).with_context(|| {
+                format!(
+                    "Failed to replace {} with the new key, move {} there by hand",
+                    file.display(),
+                    staged.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StagedKey {
+    fn drop(&mut self) {
+        if let Some((staged, _)) = &self.files {
+            let _ = ::std::fs::remove_file(staged);
+        }
+    }
+}
+
+impl SecretStorage {
+    /// Get ready to replace the stored secret key with `sk`, without losing the current one yet.
+    /// An inline key is replaced right away, and takes effect once the config is saved. A key
+    /// file only is once the returned `StagedKey` is committed. `passphrase` is only needed if
+    /// the key is stored encrypted.
+    pub(crate) fn stage(
+        &mut self,
+        base: &Path,
+        sk: &SecretKey,
+        passphrase: Option<&str>,
+    ) -> Result<StagedKey> {
+        let (file, contents) = match self {
+            Self::Inline(inline) => {
+                inline.copy_from_slice(sk.as_ref());
+                return Ok(StagedKey { files: None });
+            }
+            Self::File { file } => (
+                base.join(file),
+                ::base64::encode_config(sk.as_ref(), ::base64::URL_SAFE_NO_PAD),
+            ),
+            Self::Encrypted { encrypted_file } => {
+                let passphrase = passphrase
+                    .with_context(|| "A passphrase is needed for the encrypted key".to_owned())?;
+                (base.join(encrypted_file), encrypt_key(sk, passphrase)?)
+            }
+            Self::Credential { credential } => {
+                return Err(anyhow!(
+                    "The secret key is stored in the systemd credential {}, which can't be \
+                     replaced from here. Move it into a key file first",
+                    credential
+                ))
+            }
+        };
+        let staged = crate::state_path(&file, ".new");
+        crate::write_private(&staged, contents.as_bytes())?;
+        Ok(StagedKey {
+            files: Some((staged, file)),
+        })
+    }
+}
+
+/// Read a passphrase from the `ENTANGLE_PASSPHRASE` environment variable, or if that's not set,
+/// from a line of stdin.
+pub fn passphrase_from_env_or_stdin() -> Result<String> {
+    if let Ok(passphrase) = ::std::env::var("ENTANGLE_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    use ::std::io::Write;
+    eprint!("Passphrase for the secret key: ");
+    ::std::io::stderr().flush()?;
+    let mut line = String::new();
+    ::std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_encrypted_key() {
+        let (_, sk) = ::sodiumoxide::crypto::box_::gen_keypair();
+        let data = super::encrypt_key(&sk, "hunter2").unwrap();
+        assert!(super::decrypt_key(data.as_bytes(), "hunter3").is_err());
+        assert_eq!(super::decrypt_key(data.as_bytes(), "hunter2").unwrap(), sk);
+    }
+
+    #[test]
+    fn test_staged_key() {
+        use super::SecretStorage;
+        let dir = ::std::env::temp_dir().join(format!("entangle-test-{}", ::std::process::id()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+        let (_, old) = ::sodiumoxide::crypto::box_::gen_keypair();
+        let (_, new) = ::sodiumoxide::crypto::box_::gen_keypair();
+        let mut storage = SecretStorage::File { file: "key".into() };
+        crate::write_private(
+            &dir.join("key"),
+            ::base64::encode_config(old.as_ref(), ::base64::URL_SAFE_NO_PAD).as_bytes(),
+        )
+        .unwrap();
+
+        // The old key is kept until the new one is committed
+        let staged = storage.stage(&dir, &new, None).unwrap();
+        assert_eq!(storage.load(&dir, || unreachable!()).unwrap(), old);
+        drop(staged);
+        assert_eq!(storage.load(&dir, || unreachable!()).unwrap(), old);
+        assert!(!crate::state_path(&dir.join("key"), ".new").exists());
+
+        storage.stage(&dir, &new, None).unwrap().commit().unwrap();
+        assert_eq!(storage.load(&dir, || unreachable!()).unwrap(), new);
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -6,40 +6,53 @@ use ::anyhow::{anyhow, Context, Result};
 use ::async_std::{fs, net::UdpSocket, sync::Arc};
 use ::cdgram::CDGramClient;
 use ::std::mem::ManuallyDrop;
-use log::{debug, info};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, info, warn};
 
 use crate::proto::ServerMessage;
 struct InputDeviceState {
     state: crate::proto::InputDevice,
     dev_file: ManuallyDrop<fs::File>,
+    /// Tells the thread forwarding the LEDs of the device to stop
+    stop_feedback: Arc<AtomicBool>,
 }
 
 impl InputDeviceState {
-    fn create(state: crate::proto::InputDevice) -> Result<Self> {
+    fn create(
+        id: u32,
+        state: crate::proto::InputDevice,
+        client: &Arc<CDGramClient<UdpSocket>>,
+        name_prefix: &str,
+    ) -> Result<Self> {
         let mut usetup = uinput::uinput_setup {
             id: ::libc::input_id {
                 vendor: state.vendor,
-                bustype: uinput::BUS_USB,
+                bustype: state.bustype,
                 product: state.product,
                 version: state.version,
             },
             name: [0; 80],
-            ff_effects_max: 0,
+            ff_effects_max: state.ff_effects_max,
         };
 
-        let name_bytes = state.name.as_bytes();
-        if name_bytes.len() > usetup.name.len() {
-            return Err(anyhow!("Device name is too long"));
+        // Cut the name short if it doesn't fit, keeping the terminating nul
+        let mut name = format!("{}{}", name_prefix, state.name);
+        while name.len() >= usetup.name.len() {
+            name.pop();
         }
-        usetup.name[0..name_bytes.len()].copy_from_slice(state.name.as_bytes());
+        usetup.name[0..name.len()].copy_from_slice(name.as_bytes());
 
         use ::nix::{fcntl::OFlag, sys::stat::Mode};
         let fd = ::nix::fcntl::open(
             "/dev/uinput",
-            OFlag::O_WRONLY | OFlag::O_NONBLOCK,
+            OFlag::O_RDWR | OFlag::O_NONBLOCK,
             Mode::empty(),
         )?;
 
+        for prop in state.props.ones() {
+            unsafe { uinput::ui_set_propbit(fd, prop as _)? };
+        }
+
         for c in state.cap.ones() {
             unsafe { uinput::ui_set_evbit(fd, c as _)? };
         }
@@ -52,23 +65,326 @@ impl InputDeviceState {
             unsafe { uinput::ui_set_relbit(fd, rel as _)? };
         }
 
+        for led in state.led_bits.ones() {
+            unsafe { uinput::ui_set_ledbit(fd, led as _)? };
+        }
+
+        for sw in state.sw_bits.ones() {
+            unsafe { uinput::ui_set_swbit(fd, sw as _)? };
+        }
+
+        for msc in state.msc_bits.ones() {
+            unsafe { uinput::ui_set_mscbit(fd, msc as _)? };
+        }
+
+        for snd in state.snd_bits.ones() {
+            unsafe { uinput::ui_set_sndbit(fd, snd as _)? };
+        }
+
+        // Custom effects can't be forwarded
+        let ff_custom = crate::evdev::FF_CUSTOM as usize;
+        for ff in state.ff_bits.ones().filter(|ff| *ff != ff_custom) {
+            unsafe { uinput::ui_set_ffbit(fd, ff as _)? };
+        }
+
+        for abs in state.abs_bits.ones() {
+            let info = state
+                .abs_info
+                .get(&(abs as u16))
+                .with_context(|| format!("Missing information about absolute axis {}", abs))?;
+            let setup = uinput::uinput_abs_setup {
+                code: abs as u16,
+                absinfo: crate::evdev::raw::input_absinfo {
+                    value: info.value,
+                    minimum: info.minimum,
+                    maximum: info.maximum,
+                    fuzz: info.fuzz,
+                    flat: info.flat,
+                    resolution: info.resolution,
+                },
+            };
+            unsafe {
+                uinput::ui_set_absbit(fd, abs as _)?;
+                uinput::ui_abs_setup(fd, &setup)?;
+            }
+        }
+
+        if let Some(phys) = &state.phys {
+            let phys = ::std::ffi::CString::new(phys.as_str())?;
+            // The argument is the string itself, not a pointer to it
+            unsafe { uinput::ui_set_phys(fd, phys.as_ptr() as *const *const ::libc::c_char)? };
+        }
+
         unsafe {
             uinput::ui_dev_setup(fd, &usetup)?;
             uinput::ui_dev_create(fd)?;
+        }
+        // Touches that are already going on
+        write_events(fd, &mt_state_events(&state))?;
+        write_events(fd, &initial_state_events(&state))?;
 
+        let stop_feedback = Arc::new(AtomicBool::new(false));
+        let has_output = state.led_bits.ones().next().is_some()
+            || state.snd_bits.ones().next().is_some()
+            || state.ff_effects_max > 0;
+        if has_output {
+            let feedback_fd = ::nix::unistd::dup(fd)?;
+            let leds = state.led_vals.clone();
+            let client = client.clone();
+            let stop = stop_feedback.clone();
+            ::std::thread::spawn(move || {
+                if let Err(e) = forward_feedback(feedback_fd, id, leds, &client, &stop) {
+                    debug!("Stopped forwarding the feedback of {}: {}", id, e);
+                }
+            });
+        }
+
+        unsafe {
             use ::async_std::os::unix::io::FromRawFd;
             Ok(Self {
                 state,
                 dev_file: ManuallyDrop::new(FromRawFd::from_raw_fd(fd)),
+                stop_feedback,
             })
         }
     }
+
+    /// Press and release keys, so the keys in `keys` are the ones pressed
+    fn sync_keys(&mut self, keys: &::fixedbitset::FixedBitSet) -> Result<()> {
+        use crate::evdev::{Synchronization, Types};
+        use ::async_std::os::unix::io::AsRawFd;
+        let mut events: Vec<_> = self
+            .state
+            .key_vals
+            .symmetric_difference(keys)
+            .filter(|k| self.state.key_bits.contains(*k))
+            .map(|k| input_event(Types::KEY.number(), k as u16, keys.contains(k) as i32))
+            .collect();
+        if !events.is_empty() {
+            debug!("Synchronizing {} keys", events.len());
+            events.push(input_event(
+                Types::SYNCHRONIZATION.number(),
+                Synchronization::SYN_REPORT as u16,
+                0,
+            ));
+            write_events(self.dev_file.as_raw_fd(), &events)?;
+        }
+        self.state.key_vals.clone_from(keys);
+        Ok(())
+    }
+}
+
+/// Tell the server what the programs on the client do to device `id`, i.e. the LEDs they set,
+/// the sounds and the force feedback effects they upload and play, until `stop` is set. That is
+/// read from `fd`, a duplicate of the uinput fd of the device. `leds` is the LEDs that are lit to
+/// begin with.
+fn forward_feedback(
+    fd: ::std::os::unix::io::RawFd,
+    id: u32,
+    mut leds: ::fixedbitset::FixedBitSet,
+    client: &CDGramClient<UdpSocket>,
+    stop: &AtomicBool,
+) -> Result<()> {
+    use crate::evdev::Types;
+    use crate::proto::DeviceOutput;
+    use ::nix::poll::{poll, PollFd, PollFlags};
+    use ::std::io::Read;
+    use ::std::os::unix::io::FromRawFd;
+    let mut file = unsafe { ::std::fs::File::from_raw_fd(fd) };
+    while !stop.load(Ordering::Relaxed) {
+        // Wake up every now and then to check whether the device is gone
+        if poll(&mut [PollFd::new(fd, PollFlags::POLLIN)], 100)? == 0 {
+            continue;
+        }
+        let mut events = [input_event(0, 0, 0); 16];
+        let data = unsafe {
+            ::std::slice::from_raw_parts_mut(
+                events.as_mut_ptr() as *mut u8,
+                ::std::mem::size_of_val(&events),
+            )
+        };
+        let len = match file.read(data) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ::std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut outputs = Vec::new();
+        let mut leds_changed = false;
+        for ev in &events[..len / ::std::mem::size_of::<::libc::input_event>()] {
+            match ev.type_ {
+                t if t == Types::LED.number::<u16>() && (ev.code as usize) < leds.len() => {
+                    leds.set(ev.code as usize, ev.value != 0);
+                    leds_changed = true;
+                }
+                t if t == Types::SOUND.number::<u16>() => {
+                    outputs.push(DeviceOutput::Sound {
+                        code: ev.code,
+                        value: ev.value,
+                    });
+                }
+                t if t == Types::FORCEFEEDBACK.number::<u16>() => {
+                    outputs.push(DeviceOutput::ForceFeedback {
+                        code: ev.code,
+                        value: ev.value,
+                    });
+                }
+                uinput::EV_UINPUT => outputs.push(ff_request(fd, ev.code, ev.value as u32)?),
+                _ => (),
+            }
+        }
+        if leds_changed {
+            outputs.push(DeviceOutput::Leds(leds.clone()));
+        }
+        for output in outputs {
+            let msg = ClientMessage::Output { device: id, output };
+            ::async_std::task::block_on(client.send(&::bincode::serialize(&msg)?))?;
+        }
+    }
+    Ok(())
+}
+
+/// Complete the force feedback request `request_id` of type `code` uinput made on `fd`. The
+/// requests always succeed, as the server is too far away to wait for.
+fn ff_request(
+    fd: ::std::os::unix::io::RawFd,
+    code: u16,
+    request_id: u32,
+) -> Result<crate::proto::DeviceOutput> {
+    use crate::proto::DeviceOutput;
+    match code {
+        uinput::UI_FF_UPLOAD => {
+            let mut upload: uinput::uinput_ff_upload = unsafe { ::std::mem::zeroed() };
+            upload.request_id = request_id;
+            unsafe { uinput::ui_begin_ff_upload(fd, &mut upload)? };
+            let effect = (&upload.effect).into();
+            upload.retval = 0;
+            unsafe { uinput::ui_end_ff_upload(fd, &upload)? };
+            Ok(DeviceOutput::UploadEffect(effect))
+        }
+        uinput::UI_FF_ERASE => {
+            let mut erase = uinput::uinput_ff_erase {
+                request_id,
+                retval: 0,
+                effect_id: 0,
+            };
+            unsafe { uinput::ui_begin_ff_erase(fd, &mut erase)? };
+            erase.retval = 0;
+            unsafe { uinput::ui_end_ff_erase(fd, &erase)? };
+            Ok(DeviceOutput::EraseEffect(erase.effect_id as i16))
+        }
+        _ => Err(anyhow!("Unknown uinput request {}", code)),
+    }
+}
+
+fn input_event(type_: u16, code: u16, value: i32) -> ::libc::input_event {
+    ::libc::input_event {
+        time: ::libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_,
+        code,
+        value,
+    }
+}
+
+fn write_events(fd: ::std::os::unix::io::RawFd, events: &[::libc::input_event]) -> Result<()> {
+    let data = unsafe {
+        ::std::slice::from_raw_parts(
+            events.as_ptr() as *const u8,
+            ::std::mem::size_of_val(events),
+        )
+    };
+    if ::nix::unistd::write(fd, data)? != data.len() {
+        return Err(anyhow!("Short write to the input device"));
+    }
+    Ok(())
+}
+
+/// Events that put a newly created device into the multitouch state of `state`: the contacts in
+/// each active slot, followed by selecting the current slot.
+fn mt_state_events(state: &crate::proto::InputDevice) -> Vec<::libc::input_event> {
+    use crate::evdev::{AbsoluteAxis, Synchronization, Types};
+    let ev_abs = Types::ABSOLUTE.number();
+    let abs_mt_slot = AbsoluteAxis::ABS_MT_SLOT.number();
+    let current = match state.abs_info.get(&abs_mt_slot) {
+        Some(info) => info.value,
+        None => return Vec::new(),
+    };
+    let tracking_ids = state
+        .mt_slots
+        .get(&AbsoluteAxis::ABS_MT_TRACKING_ID.number());
+    let mut codes: Vec<_> = state.mt_slots.keys().copied().collect();
+    codes.sort_unstable();
+    let slots = state.mt_slots.values().map(Vec::len).max().unwrap_or(0);
+    let mut events = Vec::new();
+    for slot in 0..slots {
+        if tracking_ids.is_some_and(|ids| ids.get(slot) == Some(&-1)) {
+            // Nothing is touching this slot
+            continue;
+        }
+        events.push(input_event(ev_abs, abs_mt_slot, slot as i32));
+        for code in &codes {
+            if let Some(val) = state.mt_slots[code].get(slot) {
+                events.push(input_event(ev_abs, *code, *val));
+            }
+        }
+    }
+    if events.is_empty() {
+        return events;
+    }
+    events.push(input_event(ev_abs, abs_mt_slot, current));
+    events.push(input_event(
+        Types::SYNCHRONIZATION.number(),
+        Synchronization::SYN_REPORT as u16,
+        0,
+    ));
+    events
+}
+
+/// Events that put a newly created device into the state of `state`: the closed switches, and
+/// the key repeat settings.
+fn initial_state_events(state: &crate::proto::InputDevice) -> Vec<::libc::input_event> {
+    use crate::evdev::{Repeat, Synchronization, Types};
+    let mut events: Vec<_> = state
+        .sw_vals
+        .ones()
+        .filter(|sw| state.sw_bits.contains(*sw))
+        .map(|sw| input_event(Types::SWITCH.number(), sw as u16, 1))
+        .collect();
+    if let Some([delay, period]) = state.repeat {
+        events.push(input_event(
+            Types::REPEAT.number(),
+            Repeat::REP_DELAY.number(),
+            delay as i32,
+        ));
+        events.push(input_event(
+            Types::REPEAT.number(),
+            Repeat::REP_PERIOD.number(),
+            period as i32,
+        ));
+    }
+    if events.is_empty() {
+        return events;
+    }
+    events.push(input_event(
+        Types::SYNCHRONIZATION.number(),
+        Synchronization::SYN_REPORT as u16,
+        0,
+    ));
+    events
 }
 
 impl Drop for InputDeviceState {
     fn drop(&mut self) {
         use ::async_std::os::unix::io::AsRawFd;
         use ::log::error;
+        self.stop_feedback.store(true, Ordering::Relaxed);
+        // Don't leave keys pressed, whatever is using them might not notice the device is gone
+        let released = ::fixedbitset::FixedBitSet::with_capacity(self.state.key_vals.len());
+        if let Err(e) = self.sync_keys(&released) {
+            error!("Failed to release the keys {}", e);
+        }
         if let Err(e) = unsafe { uinput::ui_dev_destroy(self.dev_file.as_raw_fd()) } {
             error!("Failed to destroy device {}", e);
         }
@@ -76,35 +392,68 @@ impl Drop for InputDeviceState {
     }
 }
 
+/// Whether `a` and `b` describe the same device, possibly in different states
+fn same_shape(a: &crate::proto::InputDevice, b: &crate::proto::InputDevice) -> bool {
+    let ranges_eq = a.abs_info.len() == b.abs_info.len()
+        && a.abs_info.iter().all(|(code, a)| {
+            b.abs_info.get(code).is_some_and(|b| {
+                (a.minimum, a.maximum, a.fuzz, a.flat, a.resolution)
+                    == (b.minimum, b.maximum, b.fuzz, b.flat, b.resolution)
+            })
+        });
+    a.cap == b.cap
+        && a.props == b.props
+        && a.key_bits == b.key_bits
+        && a.rel_bits == b.rel_bits
+        && a.led_bits == b.led_bits
+        && a.sw_bits == b.sw_bits
+        && a.msc_bits == b.msc_bits
+        && a.snd_bits == b.snd_bits
+        && a.repeat.is_some() == b.repeat.is_some()
+        && a.ff_bits == b.ff_bits
+        && a.ff_effects_max == b.ff_effects_max
+        && a.abs_bits == b.abs_bits
+        && ranges_eq
+        && a.name == b.name
+        && a.bustype == b.bustype
+        && a.phys == b.phys
+        && a.uniq == b.uniq
+        && a.vendor == b.vendor
+        && a.product == b.product
+        && a.version == b.version
+}
+
 async fn handle_packet(
     pkt: ServerMessage,
     devices: &mut HashMap<u32, InputDeviceState>,
+    client: &Arc<CDGramClient<UdpSocket>>,
+    name_prefix: &str,
 ) -> Result<()> {
-    use ::futures::AsyncWriteExt;
     match pkt {
         ServerMessage::Sync(devs) => {
             for (id, update) in devs {
                 use crate::proto::InputDeviceUpdate::*;
                 match update {
                     Update(state) => {
-                        if let Some(old_device) = devices.get(&id) {
-                            if old_device.state.cap != state.cap
-                                || old_device.state.key_bits != state.key_bits
-                                || old_device.state.rel_bits != state.rel_bits
-                                || old_device.state.name != state.name
-                                || old_device.state.vendor != state.vendor
-                                || old_device.state.product != state.product
-                                || old_device.state.version != state.version
-                            {
+                        let state = *state;
+                        if let Some(old_device) = devices.get_mut(&id) {
+                            if !same_shape(&old_device.state, &state) {
                                 // Recreate the device
                                 devices.remove(&id);
-                                devices.insert(id, InputDeviceState::create(state)?);
+                                devices.insert(
+                                    id,
+                                    InputDeviceState::create(id, state, client, name_prefix)?,
+                                );
                             } else {
-                                // Sychronize the key_vals
+                                old_device.sync_keys(&state.key_vals)?;
+                                old_device.state = state;
                             }
                         } else {
                             debug!("Got new input device {}:{:?}", id, state);
-                            devices.insert(id, InputDeviceState::create(state)?);
+                            devices.insert(
+                                id,
+                                InputDeviceState::create(id, state, client, name_prefix)?,
+                            );
                         }
                     }
                     Drop => {
@@ -113,66 +462,170 @@ async fn handle_packet(
                 }
             }
         }
-        ServerMessage::Event((dev_id, ev)) => {
-            debug!("Received event for {}, {:?}", dev_id, ev);
+        ServerMessage::Frame((dev_id, n, frame)) => {
+            debug!("Received events for {}, {:?}", dev_id, frame);
             if let Some(state) = devices.get_mut(&dev_id) {
-                let ev = ::libc::input_event {
-                    time: ::libc::timeval {
-                        tv_sec: 0,
-                        tv_usec: 0,
-                    },
-                    type_: ev.type_,
-                    code: ev.code,
-                    value: ev.value,
-                };
-                debug!("Writing device event {:?}", ev);
-                let data = unsafe {
-                    ::std::slice::from_raw_parts(
-                        &ev as *const _ as *const _,
-                        ::std::mem::size_of_val(&ev),
-                    )
-                };
-                state.dev_file.write(data).await?;
-                if (ev.type_ as u32) == crate::evdev::Types::SYNCHRONIZATION.bits().trailing_zeros()
-                {
-                    debug!("Flushing {:?}", ev);
-                    state.dev_file.flush().await?;
+                state.state.frames = state.state.frames.max(n);
+                use crate::evdev::Types;
+                use ::async_std::os::unix::io::AsRawFd;
+                let mut events = Vec::with_capacity(frame.len());
+                for ev in frame {
+                    state.state.apply(ev.type_, ev.code, ev.value);
+                    // The LEDs and sounds are up to the programs on the client
+                    if ev.type_ != Types::LED.number::<u16>()
+                        && ev.type_ != Types::SOUND.number::<u16>()
+                    {
+                        events.push(input_event(ev.type_, ev.code, ev.value));
+                    }
                 }
-                debug!("Write done {:?}", ev);
+                // All at once, so programs reading the device never see half a frame
+                write_events(state.dev_file.as_raw_fd(), &events)?;
+                debug!("Write done");
             }
         }
-        ServerMessage::Pong => {}
+        ServerMessage::KeyState(state) => {
+            for (id, (n, pressed)) in state {
+                if let Some(device) = devices.get_mut(&id) {
+                    if n < device.state.frames {
+                        // Older than a frame we already have
+                        continue;
+                    }
+                    device.state.frames = n;
+                    let mut keys =
+                        ::fixedbitset::FixedBitSet::with_capacity(device.state.key_vals.len());
+                    for key in pressed {
+                        if (key as usize) < keys.len() {
+                            keys.insert(key as usize);
+                        }
+                    }
+                    device.sync_keys(&keys)?;
+                }
+            }
+        }
+        ServerMessage::Pong
+        | ServerMessage::KeyRotated
+        | ServerMessage::KeyRotation(_)
+        | ServerMessage::Unpaired => {}
     };
     Ok(())
 }
 
+/// Make sure we can create input devices, so a missing permission is reported up front instead
+/// of as a failure to create the first device.
+pub(crate) fn check_uinput_access() -> Result<()> {
+    use ::nix::unistd::{access, AccessFlags};
+    access("/dev/uinput", AccessFlags::R_OK | AccessFlags::W_OK).map_err(|e| {
+        anyhow!(
+            "Can't open /dev/uinput for writing: {}. Either run as root, or give your user \
+             access to it, e.g. with the udev rule \
+             `KERNEL==\"uinput\", GROUP=\"uinput\", MODE=\"0660\"` and adding your user to the \
+             uinput group",
+            e
+        )
+    })
+}
+
+/// Listen for servers announcing themselves on the local network
+pub(crate) fn discover_servers() -> Result<Vec<(::std::net::SocketAddr, ::discovery::Beacon)>> {
+    ::discovery::discover(
+        ::discovery::Kind::Server,
+        ::std::time::Duration::from_secs(3),
+    )
+}
+
+/// Find the peer matching `server`. `server` either matches the name or the host name of the
+/// peer as written in the config file, or is an ip address the peer currently resolves to. If
+/// none does, look for a paired server announcing itself under that name on the local network.
+async fn find_server<'a>(
+    global_cfg: &'a ::config::Config,
+    server: &str,
+) -> Result<(&'a ::config::Peer, ::config::PeerAddr)> {
+    let peers = global_cfg
+        .peers
+        .iter()
+        .filter_map(|peer| peer.addr.as_ref().map(|addr| (peer, addr)));
+    if let Some((peer, addr)) = peers
+        .clone()
+        .find(|(peer, addr)| peer.name.as_deref() == Some(server) || addr.host() == server)
+    {
+        return Ok((peer, addr.clone()));
+    }
+    if let Ok(ip) = server.parse::<::std::net::IpAddr>() {
+        for (peer, addr) in peers {
+            let addr2 = addr.clone();
+            let resolved = ::async_std::task::spawn_blocking(move || addr2.resolve()).await;
+            match resolved {
+                Ok(resolved) if resolved.iter().any(|a| a.ip() == ip) => {
+                    return Ok((peer, addr.clone()))
+                }
+                Ok(_) => (),
+                Err(e) => debug!("Failed to resolve {}: {}", addr, e),
+            }
+        }
+    }
+    debug!("Looking for {} on the local network", server);
+    let found = ::async_std::task::spawn_blocking(discover_servers).await;
+    for (addr, beacon) in found.unwrap_or_else(|e| {
+        info!("Failed to look for servers: {}", e);
+        Vec::new()
+    }) {
+        let peer = global_cfg
+            .peers
+            .iter()
+            .find(|p| p.public_keys().any(|k| k.as_ref() == &beacon.public[..]));
+        if let Some(peer) = peer {
+            if beacon.name == server || peer.name.as_deref() == Some(server) {
+                return Ok((peer, addr.into()));
+            }
+        }
+    }
+    Err(anyhow!("Unpaired server {}", server))
+}
+
 pub(crate) async fn run(
     global_cfg: &::config::Config,
     cfg: &super::EntangledClientOpts,
 ) -> Result<!> {
     use ::async_std::future::timeout;
-    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-
-    let mut server = None;
-    for peer in global_cfg.peers.iter() {
-        if let Some(addr) = peer.addr {
-            if addr.ip() == cfg.server {
-                server = Some((peer.public(), addr));
-                break;
-            }
-        }
-    }
-    let (server_pk, server_addr) = server.with_context(|| "Unpaired server".to_owned())?;
+
+    let (server, server_addr) = find_server(global_cfg, &cfg.server).await?;
     let mut devices = HashMap::<u32, InputDeviceState>::new();
 
-    let mut client = CDGramClient::new(global_cfg.public(), global_cfg.secret(), server_pk, socket);
-    timeout(std::time::Duration::from_secs(1), async {
-        client.connect(server_addr).await?;
-        client
-            .send(&::bincode::serialize(&ClientMessage::Sync(HashMap::new()))?)
+    // Resolve the server address on every connection attempt, it might have changed since the
+    // last time.
+    let server_addrs = {
+        let addr = server_addr.clone();
+        ::async_std::task::spawn_blocking(move || addr.resolve())
             .await
-    })
-    .await.with_context(|| "Timed out establishing connection".to_owned())??;
+            .with_context(|| format!("Failed to resolve {}", server_addr))?
+    };
+    // Right after the server rotated its key, it might still be using its previous one
+    let mut connection = None;
+    'connect: for server_pk in server.public_keys() {
+        for &addr in &server_addrs {
+            debug!("Connecting to {} at {}", server_addr, addr);
+            let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+            let mut client =
+                CDGramClient::new(global_cfg.public(), global_cfg.secret(), server_pk, socket);
+            let result = timeout(std::time::Duration::from_secs(1), async {
+                client.connect(addr).await?;
+                client
+                    .send(&::bincode::serialize(&ClientMessage::Sync(HashMap::new()))?)
+                    .await
+            })
+            .await;
+            match result {
+                Ok(Ok(_)) => {
+                    connection = Some((client, server_pk));
+                    break 'connect;
+                }
+                Ok(Err(e)) => info!("Failed to connect to {} at {}: {}", server_addr, addr, e),
+                Err(_) => info!("Timed out connecting to {} at {}", server_addr, addr),
+            }
+        }
+    }
+    let (client, server_pk) =
+        connection.with_context(|| format!("Failed to establish connection to {}", server_addr))?;
     let client = Arc::new(client);
     let mut keepalive: Option<async_std::task::JoinHandle<()>> = None;
     let mut pong_pending = false;
@@ -198,7 +651,23 @@ pub(crate) async fn run(
                     .unwrap_or_else(|e| info!("Failed to send keep alive {}", e));
             }));
             let pkt: ServerMessage = ::bincode::deserialize(&pkt)?;
-            handle_packet(pkt, &mut devices).await?;
+            if let ServerMessage::KeyRotation(rotation) = &pkt {
+                crate::rotate::accept_key_rotation(
+                    server_pk,
+                    rotation,
+                    &global_cfg.secret(),
+                    global_cfg.path(),
+                )
+                .await
+                .unwrap_or_else(|e| warn!("Failed to accept the new server key: {:#}", e));
+            } else if let ServerMessage::Unpaired = pkt {
+                crate::unpair::accept_unpairing(server_pk, global_cfg.path())
+                    .await
+                    .unwrap_or_else(|e| warn!("Failed to forget the server: {:#}", e));
+                return Err(anyhow!("{} has unpaired from us", server_addr));
+            } else {
+                handle_packet(pkt, &mut devices, &client, &cfg.name_prefix).await?;
+            }
             pong_pending = false;
         } else {
             // Timeout receiving
@@ -214,3 +683,35 @@ pub(crate) async fn run(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::tests::*;
+
+    fn events(events: Vec<::libc::input_event>) -> Vec<(u16, u16, i32)> {
+        events
+            .into_iter()
+            .map(|ev| (ev.type_, ev.code, ev.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_mt_state_events() {
+        let mut state = touchpad();
+        assert!(super::mt_state_events(&state).is_empty());
+
+        // Only the second slot is touched, and the first one is selected
+        state.mt_slots.get_mut(&ABS_MT_POSITION_X).unwrap()[1] = 100;
+        state.mt_slots.get_mut(&ABS_MT_TRACKING_ID).unwrap()[1] = 7;
+        assert_eq!(
+            events(super::mt_state_events(&state)),
+            vec![
+                (EV_ABS, ABS_MT_SLOT, 1),
+                (EV_ABS, ABS_MT_POSITION_X, 100),
+                (EV_ABS, ABS_MT_TRACKING_ID, 7),
+                (EV_ABS, ABS_MT_SLOT, 0),
+                SYN,
+            ]
+        );
+    }
+}
@@ -39,10 +39,12 @@ pub mod raw;
 use ::anyhow::Result;
 use ::bitflags::bitflags;
 use ::fixedbitset::FixedBitSet;
+use ::log::debug;
 use ::serde_derive::{Deserialize, Serialize};
+use ::std::collections::{HashMap, VecDeque};
 use ::std::ffi::{CStr, CString};
 use ::std::os::unix::io::*;
-use ::std::path::Path;
+use ::std::path::{Path, PathBuf};
 
 pub use FFEffect::*;
 pub use Key::*;
@@ -143,6 +145,12 @@ bitflags! {
     }
 }
 
+impl Into<FixedBitSet> for Props {
+    fn into(self) -> FixedBitSet {
+        FixedBitSet::with_capacity_and_blocks(32, ::std::iter::once(self.bits()))
+    }
+}
+
 include!("scancodes.rs"); // it's a huge glob of text that I'm tired of skipping over.
 
 bitflags! {
@@ -236,8 +244,8 @@ impl Into<FixedBitSet> for AbsoluteAxis {
     fn into(self) -> FixedBitSet {
         let bits = self.bits();
         FixedBitSet::with_capacity_and_blocks(
-            32,
-            ::std::array::IntoIter::new([(bits >> 32) as u32, (bits & 0xffff_ffff) as u32]),
+            64,
+            ::std::array::IntoIter::new([(bits & 0xffff_ffff) as u32, (bits >> 32) as u32]),
         )
     }
 }
@@ -280,6 +288,12 @@ bitflags! {
     }
 }
 
+impl Into<FixedBitSet> for Switch {
+    fn into(self) -> FixedBitSet {
+        FixedBitSet::with_capacity_and_blocks(32, ::std::iter::once(self.bits()))
+    }
+}
+
 bitflags! {
     /// LEDs specified by USB HID.
     pub struct Led: u32 {
@@ -302,6 +316,12 @@ bitflags! {
     }
 }
 
+impl Into<FixedBitSet> for Led {
+    fn into(self) -> FixedBitSet {
+        FixedBitSet::with_capacity_and_blocks(32, ::std::iter::once(self.bits()))
+    }
+}
+
 bitflags! {
     /// Various miscellaneous event types. Current as of kernel 4.1.
     pub struct Misc: u32 {
@@ -321,6 +341,12 @@ bitflags! {
     }
 }
 
+impl Into<FixedBitSet> for Misc {
+    fn into(self) -> FixedBitSet {
+        FixedBitSet::with_capacity_and_blocks(32, ::std::iter::once(self.bits()))
+    }
+}
+
 bitflags! {
     pub struct FFStatus: u32 {
         const FF_STATUS_STOPPED	= 1 << 0x00;
@@ -365,6 +391,12 @@ bitflags! {
     }
 }
 
+impl Into<FixedBitSet> for Sound {
+    fn into(self) -> FixedBitSet {
+        FixedBitSet::with_capacity_and_blocks(32, ::std::iter::once(self.bits()))
+    }
+}
+
 macro_rules! impl_number {
     ($($t:ident),*) => {
         $(impl $t {
@@ -407,6 +439,9 @@ pub enum Synchronization {
     SYN_DROPPED = 3,
 }
 
+/// Number of absolute axes, i.e. `ABS_CNT`
+const ABS_CNT: u32 = 0x40;
+
 #[derive(Clone)]
 pub struct DeviceState {
     /// The state corresponds to kernel state at this timestamp.
@@ -414,14 +449,120 @@ pub struct DeviceState {
     /// Set = key pressed
     pub key_vals: FixedBitSet,
     pub abs_vals: Vec<input_absinfo>,
+    /// The value in each slot, for each multitouch axis but `ABS_MT_SLOT`. `abs_vals` only has
+    /// the values in the current slot.
+    pub mt_vals: HashMap<u16, Vec<i32>>,
     /// Set = switch enabled (closed)
     pub switch_vals: FixedBitSet,
     /// Set = LED lit
     pub led_vals: FixedBitSet,
+    /// Set = sound playing
+    pub snd_vals: FixedBitSet,
+    /// Key repeat delay and period, in ms
+    pub rep_vals: [u32; 2],
+}
+
+impl DeviceState {
+    /// Update the state with an event the device generated
+    fn apply(&mut self, ev: &::libc::input_event) {
+        let code = ev.code as usize;
+        let pressed = ev.value != 0;
+        match ev.type_ {
+            t if t == Types::KEY.number::<u16>() && code < self.key_vals.len() => {
+                self.key_vals.set(code, pressed)
+            }
+            t if t == Types::SWITCH.number::<u16>() && code < self.switch_vals.len() => {
+                self.switch_vals.set(code, pressed)
+            }
+            t if t == Types::LED.number::<u16>() && code < self.led_vals.len() => {
+                self.led_vals.set(code, pressed)
+            }
+            t if t == Types::ABSOLUTE.number::<u16>() && code < self.abs_vals.len() => {
+                let slot = self.abs_vals[AbsoluteAxis::ABS_MT_SLOT.number::<usize>()].value;
+                if let Some(vals) = self.mt_vals.get_mut(&ev.code) {
+                    if let Some(val) = vals.get_mut(slot as usize) {
+                        *val = ev.value;
+                    }
+                }
+                self.abs_vals[code].value = ev.value;
+            }
+            _ => (),
+        }
+        self.timestamp = ev.time;
+    }
+
+    /// Events that take a consumer from state `old` to this state, of a device with the absolute
+    /// axes `abs`, ending with `SYN_REPORT`. Empty if nothing has changed.
+    fn delta(&self, old: &DeviceState, abs: AbsoluteAxis) -> VecDeque<::libc::input_event> {
+        let new = self;
+        let mut events = VecDeque::new();
+        for (ty, old, new) in [
+            (Types::KEY, &old.key_vals, &new.key_vals),
+            (Types::SWITCH, &old.switch_vals, &new.switch_vals),
+            (Types::LED, &old.led_vals, &new.led_vals),
+        ] {
+            events.extend(
+                old.symmetric_difference(new)
+                    .map(|code| event(ty, code as u16, new.contains(code) as i32)),
+            );
+        }
+
+        let slot = AbsoluteAxis::ABS_MT_SLOT.number::<usize>();
+        let axes = new.abs_vals.len().min(old.abs_vals.len());
+        for code in 0..axes {
+            let multitouch = code == slot || new.mt_vals.contains_key(&(code as u16));
+            if abs.bits() & (1 << code) == 0 || multitouch {
+                continue;
+            }
+            if old.abs_vals[code].value != new.abs_vals[code].value {
+                events.push_back(event(
+                    Types::ABSOLUTE,
+                    code as u16,
+                    new.abs_vals[code].value,
+                ));
+            }
+        }
+        if abs.contains(AbsoluteAxis::ABS_MT_SLOT) && slot < axes {
+            // Slot by slot, selecting each slot that has changed
+            let mut codes: Vec<_> = new.mt_vals.keys().copied().collect();
+            codes.sort_unstable();
+            let slots = new.mt_vals.values().map(Vec::len).max().unwrap_or(0);
+            let mut current = old.abs_vals[slot].value;
+            for s in 0..slots {
+                for code in &codes {
+                    let val = match new.mt_vals[code].get(s) {
+                        Some(val) => *val,
+                        None => continue,
+                    };
+                    if old.mt_vals.get(code).and_then(|vals| vals.get(s)) == Some(&val) {
+                        continue;
+                    }
+                    if current != s as i32 {
+                        events.push_back(event(Types::ABSOLUTE, slot as u16, s as i32));
+                        current = s as i32;
+                    }
+                    events.push_back(event(Types::ABSOLUTE, *code, val));
+                }
+            }
+            if current != new.abs_vals[slot].value {
+                events.push_back(event(
+                    Types::ABSOLUTE,
+                    slot as u16,
+                    new.abs_vals[slot].value,
+                ));
+            }
+        }
+
+        if !events.is_empty() {
+            events.push_back(event(Types::SYNCHRONIZATION, SYN_REPORT as u16, 0));
+        }
+        events
+    }
 }
 
 pub struct Device {
     file: ::async_std::fs::File,
+    path: PathBuf,
     ty: Types,
     name: CString,
     phys: Option<CString>,
@@ -436,12 +577,14 @@ pub struct Device {
     led: Led,
     misc: Misc,
     ff: FixedBitSet,
+    /// How many force feedback effects can be uploaded at the same time
+    ff_effects: i32,
     ff_stat: FFStatus,
     rep: Repeat,
     snd: Sound,
     clock: libc::c_int,
-    // pending_events[last_seen..] is the events that have occurred since the last sync.
-    last_seen: usize,
+    /// Events made up after the kernel dropped some, that haven't been returned yet
+    pending_events: VecDeque<::libc::input_event>,
     state: DeviceState,
 }
 
@@ -499,7 +642,8 @@ impl std::fmt::Debug for Device {
             ds.field("rep", &self.rep);
         }
         if self.ty.contains(Types::FORCEFEEDBACK) {
-            ds.field("ff", &self.ff);
+            ds.field("ff", &self.ff)
+                .field("ff_effects", &self.ff_effects);
         }
         if self.ty.contains(Types::POWER) {}
         if self.ty.contains(Types::FORCEFEEDBACKSTATUS) {
@@ -668,6 +812,11 @@ impl Device {
         &self.name
     }
 
+    /// Path of the device node this device was opened from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     pub fn physical_path(&self) -> &Option<CString> {
         &self.phys
     }
@@ -720,6 +869,16 @@ impl Device {
         self.snd
     }
 
+    /// Supported force feedback effect types and parameters (`FF_*`)
+    pub fn ff_supported(&self) -> &FixedBitSet {
+        &self.ff
+    }
+
+    /// How many force feedback effects can be uploaded at the same time
+    pub fn ff_effects_max(&self) -> u32 {
+        self.ff_effects.max(0) as u32
+    }
+
     pub fn state(&self) -> &DeviceState {
         &self.state
     }
@@ -736,6 +895,7 @@ impl Device {
 
         let mut dev = Device {
             file,
+            path: path.as_ref().to_owned(),
             ty: Types::empty(),
             name: unsafe { CString::from_vec_unchecked(Vec::new()) },
             phys: None,
@@ -750,10 +910,11 @@ impl Device {
             led: Led::empty(),
             misc: Misc::empty(),
             ff: FixedBitSet::with_capacity(FF_MAX as usize + 1),
+            ff_effects: 0,
             ff_stat: FFStatus::empty(),
             rep: Repeat::empty(),
             snd: Sound::empty(),
-            last_seen: 0,
+            pending_events: VecDeque::new(),
             state: DeviceState {
                 timestamp: libc::timeval {
                     tv_sec: 0,
@@ -761,8 +922,11 @@ impl Device {
                 },
                 key_vals: FixedBitSet::with_capacity(KEY_MAX as usize + 1),
                 abs_vals: vec![],
+                mt_vals: HashMap::new(),
                 switch_vals: FixedBitSet::with_capacity(0x10),
                 led_vals: FixedBitSet::with_capacity(0x10),
+                snd_vals: FixedBitSet::with_capacity(0x08),
+                rep_vals: [0; 2],
             },
             clock: libc::CLOCK_REALTIME,
         };
@@ -823,7 +987,7 @@ impl Device {
             ));
             dev.abs =
                 AbsoluteAxis::from_bits(bits64).expect("evdev: unexpected abs bits! report a bug");
-            dev.state.abs_vals = vec![input_absinfo::default(); 0x3f];
+            dev.state.abs_vals = vec![input_absinfo::default(); ABS_CNT as usize];
         }
 
         if dev.ty.contains(Types::SWITCH) {
@@ -857,7 +1021,15 @@ impl Device {
             dev.misc = Misc::from_bits(bits).expect("evdev: unexpected misc bits! report a bug");
         }
 
-        //do_ioctl!(eviocgbit(fd, ffs(FORCEFEEDBACK.bits()), 0x7f, &mut bits as *mut u32 as *mut u8));
+        if dev.ty.contains(Types::FORCEFEEDBACK) {
+            do_ioctl!(eviocgbit(
+                fd,
+                Types::FORCEFEEDBACK.number(),
+                (dev.ff.len() / 8) as libc::c_int,
+                dev.ff.as_mut_slice().as_mut_ptr() as *mut u8
+            ));
+            do_ioctl!(eviocgeffects(fd, &mut dev.ff_effects));
+        }
 
         if dev.ty.contains(Types::SOUND) {
             do_ioctl!(eviocgbit(
@@ -886,18 +1058,27 @@ impl Device {
             ));
         }
         if self.ty.contains(Types::ABSOLUTE) {
-            for idx in 0..0x28 {
-                let abs = 1 << idx;
-                // ignore multitouch, we'll handle that later.
-                if abs < AbsoluteAxis::ABS_MT_SLOT.bits() && self.abs.bits() & abs != 0 {
-                    do_ioctl!(eviocgabs(
-                        fd,
-                        idx as u32,
-                        &mut self.state.abs_vals[idx as usize]
-                    ));
+            for idx in 0..ABS_CNT {
+                if self.abs.bits() & (1 << idx) != 0 {
+                    do_ioctl!(eviocgabs(fd, idx, &mut self.state.abs_vals[idx as usize]));
                 }
             }
         }
+        if self.abs.contains(AbsoluteAxis::ABS_MT_SLOT) {
+            let slot: u32 = AbsoluteAxis::ABS_MT_SLOT.number();
+            let slots = (self.state.abs_vals[slot as usize].maximum + 1).max(0) as usize;
+            for idx in (slot + 1)..ABS_CNT {
+                if self.abs.bits() & (1 << idx) == 0 {
+                    continue;
+                }
+                // The axis code goes in, the values of each slot come out after it
+                let mut vals = vec![0i32; slots + 1];
+                vals[0] = idx as i32;
+                do_ioctl!(eviocgmtslots(fd, to_bytes_mut(&mut vals[..])));
+                vals.remove(0);
+                self.state.mt_vals.insert(idx as u16, vals);
+            }
+        }
         if self.ty.contains(Types::SWITCH) {
             do_ioctl!(eviocgsw(
                 fd,
@@ -910,11 +1091,21 @@ impl Device {
                 to_bytes_mut(self.state.led_vals.as_mut_slice())
             ));
         }
+        if self.ty.contains(Types::SOUND) {
+            do_ioctl!(eviocgsnd(
+                fd,
+                to_bytes_mut(self.state.snd_vals.as_mut_slice())
+            ));
+        }
+        if self.ty.contains(Types::REPEAT) {
+            do_ioctl!(eviocgrep(fd, &mut self.state.rep_vals));
+        }
 
         Ok(())
     }
 
-    /// Exposes the raw evdev events without doing synchronization on SYN_DROPPED.
+    /// Exposes the raw evdev events without doing synchronization on SYN_DROPPED. See
+    /// `next_synced_event` for that.
     pub async fn next_event<'a>(&'a mut self) -> Result<::libc::input_event> {
         use ::async_std::io::ReadExt;
         let mut buf: [::libc::input_event; 1] =
@@ -924,6 +1115,113 @@ impl Device {
             .await?;
         Ok(buf[0])
     }
+
+    /// The next event, keeping the state up to date. When the kernel has dropped events, the
+    /// events up to the next `SYN_REPORT` are discarded, the state is synchronized, and events
+    /// that take the consumer from the old state to the new one are returned instead, so the
+    /// stream stays consistent.
+    pub async fn next_synced_event(&mut self) -> Result<::libc::input_event> {
+        let syn = Types::SYNCHRONIZATION.number::<u16>();
+        loop {
+            if let Some(ev) = self.pending_events.pop_front() {
+                return Ok(ev);
+            }
+            let ev = self.next_event().await?;
+            if ev.type_ == syn && ev.code == SYN_DROPPED as u16 {
+                debug!("{} dropped events, synchronizing", self.path.display());
+                // The events until the next report are incomplete
+                loop {
+                    let ev = self.next_event().await?;
+                    if ev.type_ == syn && ev.code == SYN_REPORT as u16 {
+                        break;
+                    }
+                }
+                let old = self.state.clone();
+                self.sync_state()?;
+                self.pending_events = self.state.delta(&old, self.abs);
+                continue;
+            }
+            self.state.apply(&ev);
+            return Ok(ev);
+        }
+    }
+
+    /// A handle to set the LEDs of this device and play sounds and force feedback effects on it
+    /// with, which can be used while its events are being read.
+    pub fn writer(&self) -> Result<DeviceWriter> {
+        let fd = ::nix::unistd::dup(self.file.as_raw_fd())?;
+        Ok(DeviceWriter {
+            file: unsafe { ::std::fs::File::from_raw_fd(fd) },
+            leds: self.led,
+        })
+    }
+}
+
+/// Sets the LEDs of a device and plays sounds and force feedback effects on it, see
+/// `Device::writer`.
+pub struct DeviceWriter {
+    file: ::std::fs::File,
+    leds: Led,
+}
+
+fn event(type_: Types, code: u16, value: i32) -> ::libc::input_event {
+    ::libc::input_event {
+        time: ::libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        type_: type_.number(),
+        code,
+        value,
+    }
+}
+
+impl DeviceWriter {
+    fn write(&self, events: &mut [::libc::input_event]) -> Result<()> {
+        let data = unsafe { to_bytes_mut(events) };
+        ::nix::unistd::write(self.file.as_raw_fd(), data)?;
+        Ok(())
+    }
+
+    /// Light the LEDs in `lit`, and turn off the others.
+    pub fn set_leds(&self, lit: &FixedBitSet) -> Result<()> {
+        let mut events: Vec<_> = (0..0x10)
+            .filter(|code| self.leds.bits() & (1 << code) != 0)
+            .map(|code| event(Types::LED, code, lit.contains(code as usize) as i32))
+            .collect();
+        events.push(event(Types::SYNCHRONIZATION, SYN_REPORT as u16, 0));
+        self.write(&mut events)
+    }
+
+    /// Play (`value` not 0) or stop a sound, like the bell
+    pub fn play_sound(&self, code: u16, value: i32) -> Result<()> {
+        self.write(&mut [
+            event(Types::SOUND, code, value),
+            event(Types::SYNCHRONIZATION, SYN_REPORT as u16, 0),
+        ])
+    }
+
+    /// Upload a force feedback effect, or update an uploaded one if `effect.id` isn't -1.
+    /// Returns the id of the effect.
+    pub fn upload_effect(&self, effect: &mut ff_effect) -> Result<i16> {
+        // The kernel writes the id of a new effect back
+        do_ioctl!(eviocsff(
+            self.file.as_raw_fd(),
+            effect as *mut ff_effect as *const ff_effect
+        ));
+        Ok(effect.id)
+    }
+
+    pub fn erase_effect(&self, id: i16) -> Result<()> {
+        do_ioctl!(eviocrmff(self.file.as_raw_fd(), id as _));
+        Ok(())
+    }
+
+    /// Write a force feedback event: play (`value` times) or stop (`value` 0) the effect with
+    /// id `code`, or set the gain or autocenter.
+    pub fn write_ff(&self, code: u16, value: i32) -> Result<()> {
+        self.write(&mut [event(Types::FORCEFEEDBACK, code, value)])
+    }
 }
 
 pub struct Events<'a>(&'a mut Device);
@@ -944,10 +1242,37 @@ pub async fn enumerate() -> Result<Vec<Device>> {
         })
         .collect();
 
-    Ok(futs
-        .filter_map(|dev| futures::future::ready(dev.ok()))
-        .collect()
-        .await)
+    let results: Vec<_> = futs.collect().await;
+    let mut denied = 0;
+    let mut devices = Vec::new();
+    for result in results {
+        match result {
+            Ok(dev) => devices.push(dev),
+            Err(e) => {
+                if let Some(e) = e.downcast_ref::<::std::io::Error>() {
+                    if e.kind() == ::std::io::ErrorKind::PermissionDenied {
+                        denied += 1;
+                    }
+                }
+            }
+        }
+    }
+    if denied > 0 {
+        const HINT: &str = "Either run as root, or add your user to the group owning the \
+                            devices (usually `input`)";
+        if devices.is_empty() {
+            return Err(::anyhow::anyhow!(
+                "Permission denied opening the devices in /dev/input. {}",
+                HINT
+            ));
+        }
+        ::log::warn!(
+            "Permission denied opening {} devices in /dev/input, they won't be forwarded. {}",
+            denied,
+            HINT
+        );
+    }
+    Ok(devices)
 }
 
 #[cfg(test)]
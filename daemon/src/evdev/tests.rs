@@ -1,2 +1,100 @@
 // woo tests! should really test compensate_dropped... I don't even know how it's *supposed* to
 // behave yet though.
+
+use super::*;
+
+/// The absolute axes of a touchpad with two slots
+fn touchpad_axes() -> AbsoluteAxis {
+    AbsoluteAxis::ABS_X
+        | AbsoluteAxis::ABS_MT_SLOT
+        | AbsoluteAxis::ABS_MT_POSITION_X
+        | AbsoluteAxis::ABS_MT_TRACKING_ID
+}
+
+/// The state of a touchpad with two slots, nothing pressed or touched
+fn touchpad_state() -> DeviceState {
+    let mut mt_vals = HashMap::new();
+    mt_vals.insert(ABS_MT_POSITION_X, vec![0; 2]);
+    mt_vals.insert(ABS_MT_TRACKING_ID, vec![-1; 2]);
+    DeviceState {
+        timestamp: libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+        key_vals: FixedBitSet::with_capacity(KEY_MAX as usize + 1),
+        abs_vals: vec![input_absinfo::default(); ABS_CNT as usize],
+        mt_vals,
+        switch_vals: FixedBitSet::with_capacity(0x10),
+        led_vals: FixedBitSet::with_capacity(0x10),
+        snd_vals: FixedBitSet::with_capacity(0x08),
+        rep_vals: [0; 2],
+    }
+}
+
+fn events(events: VecDeque<::libc::input_event>) -> Vec<(u16, u16, i32)> {
+    events
+        .into_iter()
+        .map(|ev| (ev.type_, ev.code, ev.value))
+        .collect()
+}
+
+const EV_KEY: u16 = 1;
+const EV_ABS: u16 = 3;
+const ABS_X: u16 = 0x00;
+const ABS_MT_SLOT: u16 = 0x2f;
+const ABS_MT_POSITION_X: u16 = 0x35;
+const ABS_MT_TRACKING_ID: u16 = 0x39;
+const SYN: (u16, u16, i32) = (0, SYN_REPORT as u16, 0);
+
+#[test]
+fn test_delta_unchanged() {
+    let mut state = touchpad_state();
+    state.key_vals.insert(KEY_A as usize);
+    state.mt_vals.get_mut(&ABS_MT_TRACKING_ID).unwrap()[1] = 7;
+    assert!(state.delta(&state.clone(), touchpad_axes()).is_empty());
+}
+
+#[test]
+fn test_delta_key_released() {
+    let mut old = touchpad_state();
+    old.key_vals.insert(KEY_A as usize);
+    let new = touchpad_state();
+    assert_eq!(
+        events(new.delta(&old, touchpad_axes())),
+        vec![(EV_KEY, KEY_A as u16, 0), SYN]
+    );
+}
+
+#[test]
+fn test_delta_slot_changed() {
+    let old = touchpad_state();
+    let mut new = touchpad_state();
+    // A finger touched down in the second slot
+    new.mt_vals.get_mut(&ABS_MT_POSITION_X).unwrap()[1] = 100;
+    new.mt_vals.get_mut(&ABS_MT_TRACKING_ID).unwrap()[1] = 7;
+    new.abs_vals[ABS_MT_SLOT as usize].value = 1;
+    new.abs_vals[ABS_X as usize].value = 100;
+    assert_eq!(
+        events(new.delta(&old, touchpad_axes())),
+        vec![
+            (EV_ABS, ABS_X, 100),
+            (EV_ABS, ABS_MT_SLOT, 1),
+            (EV_ABS, ABS_MT_POSITION_X, 100),
+            (EV_ABS, ABS_MT_TRACKING_ID, 7),
+            SYN
+        ]
+    );
+
+    // And lifted again, back in the first slot
+    let mut newer = touchpad_state();
+    newer.abs_vals[ABS_X as usize].value = 100;
+    assert_eq!(
+        events(newer.delta(&new, touchpad_axes())),
+        vec![
+            (EV_ABS, ABS_MT_POSITION_X, 0),
+            (EV_ABS, ABS_MT_TRACKING_ID, -1),
+            (EV_ABS, ABS_MT_SLOT, 0),
+            SYN
+        ]
+    );
+}
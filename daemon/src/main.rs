@@ -1,9 +1,8 @@
 #![feature(option_unwrap_none, never_type, exhaustive_patterns, array_value_iter)]
 use ::anyhow::Result;
-use ::std::path::{Path, PathBuf};
+use ::std::path::PathBuf;
 
 use ::argh::FromArgs;
-use ::async_std::net::IpAddr;
 use log::info;
 
 /// Entangled subcommands
@@ -12,31 +11,87 @@ use log::info;
 enum EntangledSubcommands {
     Server(EntangledServerOpts),
     Client(EntangledClientOpts),
+    ShowConfig(EntangledShowConfigOpts),
+    RotateKey(EntangledRotateKeyOpts),
+    Discover(EntangledDiscoverOpts),
+    PairingWindow(EntangledPairingWindowOpts),
+    Unpair(EntangledUnpairOpts),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "server")]
 /// Start an entangle server
-struct EntangledServerOpts {}
+struct EntangledServerOpts {
+    #[argh(switch)]
+    /// announce the server on the local network, so clients can find it by name
+    announce: bool,
+}
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "client")]
 /// Connect to an entangle server
 struct EntangledClientOpts {
     #[argh(option, short = 's')]
-    /// server address, must be one of the peers in your config file
-    server: IpAddr,
+    /// server name, host name or ip address, must be one of the peers in your config file
+    server: String,
+    #[argh(option, default = "String::new()")]
+    /// prefix for the names of the forwarded devices, to tell them apart from local ones
+    name_prefix: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "show-config")]
+/// Print the effective configuration, after merging the drop-in files
+struct EntangledShowConfigOpts {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "rotate-key")]
+/// Replace your key pair with a new one, and tell your peers about it
+struct EntangledRotateKeyOpts {
+    #[argh(option)]
+    /// number of days peers keep accepting the old key (default: 7)
+    grace: Option<u64>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "discover")]
+/// List the servers announcing themselves on the local network
+struct EntangledDiscoverOpts {}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "pairing-window")]
+/// Let clients pair with the running server for a while, with `pair -s <server> --code`
+struct EntangledPairingWindowOpts {
+    #[argh(option, default = "5")]
+    /// number of minutes clients can pair for (default: 5)
+    minutes: u64,
+    #[argh(option)]
+    /// pre-agreed secret clients have to pair with, instead of a new one-time code
+    secret: Option<String>,
+    #[argh(switch)]
+    /// stop letting clients pair
+    close: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "unpair")]
+/// Forget a peer, and tell it to forget you too
+struct EntangledUnpairOpts {
+    #[argh(positional)]
+    /// name, host name or key fingerprint of the peer
+    peer: String,
+    #[argh(switch)]
+    /// only forget the peer, without telling it
+    local: bool,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Entangled
 struct EntangledOpts {
-    #[argh(
-        option,
-        short = 'c',
-        default = "Path::new(\"/etc/entangle.conf\").into()"
-    )]
-    /// path to your configuration file. (default: /etc/entangle.conf)
+    #[argh(option, short = 'c', default = "::config::default_path()")]
+    /// path to your configuration file, drop-in files are read from the directory with the same
+    /// name but with a .d extension. (default: /etc/entangle.conf for root,
+    /// ~/.config/entangle/entangle.conf for everyone else)
     config: PathBuf,
     #[argh(subcommand)]
     subcommand: EntangledSubcommands,
@@ -44,18 +99,46 @@ struct EntangledOpts {
 
 mod client;
 mod evdev;
+mod pairing;
 mod proto;
+mod reload;
+mod rotate;
 mod server;
 mod uinput;
+mod unpair;
 
 fn main() -> Result<()> {
     ::env_logger::init();
-    let opts: EntangledOpts = argh::from_env();
-    let cfg: ::config::Config = ::toml::from_str(&::std::fs::read_to_string(&opts.config)?)?;
+    let EntangledOpts { config, subcommand } = argh::from_env();
+    let load_config = || ::config::Config::load(&config, ::config::passphrase_from_env_or_stdin);
     use EntangledSubcommands::*;
-    match opts.subcommand {
-        Server(server) => ::async_std::task::block_on(server::run(cfg, server))?,
+    match subcommand {
+        ShowConfig(_) => {
+            print!("{}", ::config::Config::read(&config)?.effective()?);
+            Ok(())
+        }
+        Discover(_) => {
+            let cfg = ::config::Config::read(&config)?;
+            for (addr, beacon) in client::discover_servers()? {
+                let peer = cfg
+                    .peers
+                    .iter()
+                    .find(|p| p.public_keys().any(|k| k.as_ref() == &beacon.public[..]));
+                let paired = match peer {
+                    Some(peer) => format!("paired as {}", peer.name.as_deref().unwrap_or("-")),
+                    None => "not paired".to_owned(),
+                };
+                println!("{} at {} ({})", beacon.name, addr, paired);
+            }
+            Ok(())
+        }
+        PairingWindow(opts) => pairing::run_window(&config, opts),
+        RotateKey(opts) => ::async_std::task::block_on(rotate::run(&config, opts)),
+        Unpair(opts) => ::async_std::task::block_on(unpair::run(&config, opts)),
+        Server(server) => ::async_std::task::block_on(server::run(load_config()?, server))?,
         Client(client) => {
+            client::check_uinput_access()?;
+            let mut cfg = load_config()?;
             use ::governor::{Quota, RateLimiter};
             use std::convert::TryInto;
             let rl = RateLimiter::direct(
@@ -66,6 +149,11 @@ fn main() -> Result<()> {
                     rl.until_ready().await;
                     let Err(e) = client::run(&cfg, &client).await;
                     info!("Restarting client because of error {}", e);
+                    // Pick up changes to the config, e.g. a new server key
+                    match cfg.reload(|| Ok(::std::env::var("ENTANGLE_PASSPHRASE")?)) {
+                        Ok(new_cfg) => cfg = new_cfg,
+                        Err(e) => info!("Failed to reload config: {:#}", e),
+                    }
                 }
             })
         }
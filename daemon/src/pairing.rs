@@ -0,0 +1,245 @@
+//! Pairing with clients through the running server daemon, on its own port, while the config
+//! file has an open pairing window.
+use ::anyhow::{anyhow, Context, Result};
+use ::async_std::channel::{Receiver, Sender};
+use ::async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use ::async_std::sync::Arc;
+use ::cdgram::CDGramServer;
+use ::config::{Config, PairingWindow, Peer};
+use ::log::{debug, error, info, warn};
+use ::pair::link::{self, Link};
+use ::pair::{Failure, Verify};
+use ::std::path::{Path, PathBuf};
+
+/// The server's socket, with the pairing packets taken out and passed on to the pairing task
+pub(crate) struct Demux {
+    sock: Arc<UdpSocket>,
+    pairing: Sender<(SocketAddr, Vec<u8>)>,
+}
+
+impl Demux {
+    pub(crate) fn new(sock: Arc<UdpSocket>, pairing: Sender<(SocketAddr, Vec<u8>)>) -> Self {
+        Self { sock, pairing }
+    }
+}
+
+#[async_trait::async_trait]
+impl ::cdgram::Socket for Demux {
+    async fn recv(&self) -> Result<(SocketAddr, Vec<u8>)> {
+        loop {
+            let (addr, buf) = ::cdgram::Socket::recv(&*self.sock).await?;
+            if link::parse(&buf).is_none() {
+                return Ok((addr, buf));
+            }
+            // Dropped if the pairing task can't keep up, the other side will retransmit
+            let _ = self.pairing.try_send((addr, buf));
+        }
+    }
+    async fn connect(
+        &self,
+        addr: impl ToSocketAddrs<Iter = impl Iterator<Item = SocketAddr> + Send + 'static>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Result<()> {
+        ::cdgram::Socket::connect(&*self.sock, addr).await
+    }
+    async fn send(&self, buf: &[u8]) -> Result<usize> {
+        ::cdgram::Socket::send(&*self.sock, buf).await
+    }
+    async fn send_to(
+        &self,
+        buf: &[u8],
+        addr: impl ToSocketAddrs<Iter = impl Iterator<Item = SocketAddr> + Send + 'static>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Result<usize> {
+        ::cdgram::Socket::send_to(&*self.sock, buf, addr).await
+    }
+}
+
+/// Add `peer` to the config file at `path`, unless it's there already
+fn add_peer(path: &Path, peer: Peer) -> Result<()> {
+    let mut cfg = Config::read(path)?;
+    if cfg.peers.iter().any(|p| p.public() == peer.public()) {
+        return Ok(());
+    }
+    cfg.peers.push(peer);
+    cfg.save(path)
+}
+
+/// The file next to the config file at `path` with the code of its pairing window. It's kept out
+/// of the config file, which is shown by `show-config` and might be shared.
+fn code_path(path: &Path) -> PathBuf {
+    ::config::state_path(path, ".pairing")
+}
+
+/// Read the code of the pairing window in the config file at `path`
+fn read_code(path: &Path) -> Result<String> {
+    let code_path = code_path(path);
+    ::std::fs::read_to_string(&code_path)
+        .with_context(|| format!("Failed to read {}", code_path.display()))
+}
+
+/// Forget the code of the pairing window in the config file at `path`
+fn remove_code(path: &Path) -> Result<()> {
+    let code_path = code_path(path);
+    match ::std::fs::remove_file(&code_path) {
+        Err(e) if e.kind() != ::std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", code_path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Close the pairing window in the config file at `path`
+fn close_window(path: &Path) -> Result<()> {
+    let mut cfg = Config::read(path)?;
+    if cfg.pairing.take().is_some() {
+        cfg.save(path)?;
+    }
+    remove_code(path)
+}
+
+/// Pair with the client at `addr` through `link`, and let it connect right away if that works
+async fn pair_client(
+    cfg: Config,
+    code: String,
+    link: Link,
+    addr: SocketAddr,
+    hello: Vec<u8>,
+    server: Arc<CDGramServer<Demux>>,
+    path: PathBuf,
+) {
+    let verify = Verify {
+        code: Some(code),
+        expect: None,
+    };
+    // Nobody is around to compare numbers, so clients always have to use the code
+    let result = ::pair::server_exchange(&cfg, &link, addr.ip(), &hello, &verify, |_| Ok(false));
+    let result = match result.await {
+        Ok(peer) => link.finish().await.map(|()| peer),
+        Err(e) => {
+            if e.downcast_ref::<Failure>() == Some(&Failure::Rejected) {
+                // One guess at the code per pairing window is all anyone gets. Closed before the
+                // link is, so the next client can't get in while the window is still open.
+                let path = path.clone();
+                if let Err(e) = ::async_std::task::spawn_blocking(move || close_window(&path)).await
+                {
+                    error!("Failed to close the pairing window: {:#}", e);
+                }
+            }
+            link.abort().await;
+            Err(e)
+        }
+    };
+    let peer = match result {
+        Ok(peer) => peer,
+        Err(e) => {
+            warn!("Failed to pair with {}: {:#}", addr, e);
+            return;
+        }
+    };
+    let pk = peer.public();
+    info!(
+        "Paired with {} at {}, key {}",
+        peer.name.as_deref().unwrap_or("-"),
+        addr,
+        peer.fingerprint()
+    );
+    // Saving the config makes us reload it, but there is no need to wait for that
+    server.authorize_key(pk, None).await;
+    if let Err(e) = ::async_std::task::spawn_blocking(move || add_peer(&path, peer)).await {
+        error!("Failed to save the new peer: {:#}", e);
+    }
+}
+
+/// Handle the pairing packets received on the server's socket, from `packets`. New clients can
+/// pair while the config file at `path` has an open pairing window.
+pub(crate) async fn run(
+    sock: Arc<UdpSocket>,
+    packets: Receiver<(SocketAddr, Vec<u8>)>,
+    server: Arc<CDGramServer<Demux>>,
+    path: PathBuf,
+) {
+    let mut session: Option<(SocketAddr, Sender<Vec<u8>>)> = None;
+    while let Ok((addr, packet)) = packets.recv().await {
+        if let Some((session_addr, tx)) = &session {
+            if !tx.is_closed() {
+                // Only one client at a time, so nobody gets more than one guess at the code
+                if *session_addr == addr {
+                    let _ = tx.try_send(packet);
+                }
+                continue;
+            }
+        }
+        let hello = match link::parse(&packet) {
+            Some((link::HELLO, hello)) => hello.to_vec(),
+            _ => continue,
+        };
+        let cfg = match Config::read(&path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                error!("Failed to read the config: {:#}", e);
+                continue;
+            }
+        };
+        if !cfg.pairing.as_ref().is_some_and(PairingWindow::is_open) {
+            debug!("{} wants to pair, but pairing isn't allowed", addr);
+            continue;
+        }
+        let code = match read_code(&path) {
+            Ok(code) => code,
+            Err(e) => {
+                error!("Failed to read the pairing code: {:#}", e);
+                continue;
+            }
+        };
+        info!("{} wants to pair", addr);
+        let (tx, incoming) = ::async_std::channel::unbounded();
+        session = Some((addr, tx));
+        let link = Link::new(sock.clone(), addr, incoming, &[link::HELLO]);
+        ::async_std::task::spawn(pair_client(
+            cfg,
+            code,
+            link,
+            addr,
+            hello,
+            server.clone(),
+            path.clone(),
+        ));
+    }
+}
+
+/// Open or close the pairing window in the config file at `path`
+pub(crate) fn run_window(path: &Path, opts: super::EntangledPairingWindowOpts) -> Result<()> {
+    let mut cfg = Config::read(path)?;
+    if opts.close {
+        cfg.pairing = None;
+        cfg.save(path)?;
+        remove_code(path)?;
+        println!("Pairing is no longer allowed");
+        return Ok(());
+    }
+    if opts.minutes == 0 {
+        return Err(anyhow!(
+            "The pairing window has to be open for at least a minute"
+        ));
+    }
+    let code = opts.secret.unwrap_or_else(::pair::pake::generate_code);
+    ::config::write_private(&code_path(path), code.as_bytes())?;
+    cfg.pairing = Some(PairingWindow {
+        until: ::config::unix_time() + opts.minutes * 60,
+    });
+    cfg.save(path)
+        .with_context(|| format!("Failed to save {}", path.display()))?;
+    println!(
+        "Clients can pair for the next {} minutes, with\n\tpair -s {}:{} --code\nand the code\n\t{}",
+        opts.minutes,
+        ::config::host_name().unwrap_or_else(|| "<this host>".to_owned()),
+        cfg.port(),
+        code
+    );
+    Ok(())
+}
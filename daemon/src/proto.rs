@@ -1,3 +1,4 @@
+use ::fixedbitset::FixedBitSet;
 use ::serde_derive::{Deserialize, Serialize};
 use ::std::collections::HashMap;
 
@@ -9,26 +10,148 @@ pub enum ClientMessage {
     Sync(HashMap<u32, InputDevice>),
     KeepAlive,
     Ping,
+    /// The client has a new key pair
+    KeyRotation(KeyRotation),
+    /// The client has forgotten the server, and the server should forget it too
+    Unpaired,
+    /// The programs on the client did something to a device, which should be done to the
+    /// physical device too
+    Output {
+        device: u32,
+        output: DeviceOutput,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeviceOutput {
+    /// The LEDs have been set, e.g. because Caps Lock has been turned on. These are all the LEDs
+    /// that should be lit now.
+    Leds(#[serde(with = "fixedbitset")] FixedBitSet),
+    /// A sound has been started (`value` not 0) or stopped, e.g. the bell
+    Sound { code: u16, value: i32 },
+    /// A force feedback effect has been uploaded, or an uploaded one has been changed. The id of
+    /// the effect is the one on the client.
+    UploadEffect(Effect),
+    /// A force feedback effect, by its id on the client, has been erased
+    EraseEffect(i16),
+    /// A force feedback event: play or stop the effect with id `code`, or set the gain or
+    /// autocenter
+    ForceFeedback { code: u16, value: i32 },
+}
+
+/// Same as `struct ff_effect`, with the parameters of the type of effect as raw bytes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Effect {
+    pub type_: u16,
+    pub id: i16,
+    pub direction: u16,
+    pub trigger_button: u16,
+    pub trigger_interval: u16,
+    pub replay_length: u16,
+    pub replay_delay: u16,
+    #[serde(with = "serde_bytes")]
+    pub params: Vec<u8>,
+}
+
+impl From<&crate::evdev::raw::ff_effect> for Effect {
+    fn from(effect: &crate::evdev::raw::ff_effect) -> Self {
+        let params = unsafe {
+            ::std::slice::from_raw_parts(
+                effect.u._bindgen_data_.as_ptr() as *const u8,
+                ::std::mem::size_of_val(&effect.u._bindgen_data_),
+            )
+        };
+        Self {
+            type_: effect._type,
+            id: effect.id,
+            direction: effect.direction,
+            trigger_button: effect.trigger.button,
+            trigger_interval: effect.trigger.interval,
+            replay_length: effect.replay.length,
+            replay_delay: effect.replay.delay,
+            params: params.to_vec(),
+        }
+    }
+}
+
+impl Effect {
+    /// The effect as a `struct ff_effect`, with id `id`. Fails for custom periodic effects,
+    /// whose waveform is behind a pointer.
+    pub fn to_raw(&self, id: i16) -> Result<crate::evdev::raw::ff_effect, &'static str> {
+        use crate::evdev::{raw, FF_CUSTOM, FF_PERIODIC};
+        let mut effect = raw::ff_effect {
+            _type: self.type_,
+            id,
+            direction: self.direction,
+            trigger: raw::ff_trigger {
+                button: self.trigger_button,
+                interval: self.trigger_interval,
+            },
+            replay: raw::ff_replay {
+                length: self.replay_length,
+                delay: self.replay_delay,
+            },
+            u: Default::default(),
+        };
+        let params = unsafe {
+            ::std::slice::from_raw_parts_mut(
+                effect.u._bindgen_data_.as_mut_ptr() as *mut u8,
+                ::std::mem::size_of_val(&effect.u._bindgen_data_),
+            )
+        };
+        if self.params.len() != params.len() {
+            return Err("malformed effect parameters");
+        }
+        params.copy_from_slice(&self.params);
+        if self.type_ == FF_PERIODIC as u16 {
+            let periodic = unsafe { &mut *effect.u.periodic() };
+            if periodic.waveform == FF_CUSTOM as u16 {
+                return Err("custom effects aren't supported");
+            }
+            periodic.custom_len = 0;
+            periodic.custom_data = ::std::ptr::null_mut();
+        }
+        Ok(effect)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ServerMessage {
     /// Sychronize the list of input devices and their states
     Sync(HashMap<u32, InputDeviceUpdate>),
-    /// Input event
-    Event((u32, InputEvent)),
+    /// A frame of input events from a device, normally ending with `SYN_REPORT`, and its number.
+    /// The frames from each device are numbered from 1.
+    Frame((u32, u64, Vec<InputEvent>)),
     Pong,
+    /// The client's new key has been recorded
+    KeyRotated,
+    /// The server has a new key pair
+    KeyRotation(KeyRotation),
+    /// The server has forgotten the client, or acknowledges that the client forgot it. Either
+    /// way the client should forget the server too.
+    Unpaired,
+    /// The keys currently pressed on each device with keys, sent every now and then so keys
+    /// don't stay pressed on the client when an event is lost. Each comes with the number of the
+    /// last frame it includes, so one that arrives after a later frame can be ignored.
+    KeyState(HashMap<u32, (u64, Vec<u16>)>),
+}
+
+/// Announcement of a new public key. The new key and the grace period, during which the old key
+/// is still accepted, are sealed with the old secret key, for the public key of the receiver.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyRotation {
+    pub nonce: Vec<u8>,
+    pub sealed: Vec<u8>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum InputDeviceUpdate {
     /// This input device has updated states
-    Update(InputDevice),
+    Update(Box<InputDevice>),
     /// This input device has been dropped from the server
     Drop,
 }
 
-use ::fixedbitset::FixedBitSet;
 #[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone)]
 pub struct InputDevice {
     /// Available keys
@@ -37,7 +160,43 @@ pub struct InputDevice {
     /// Available relative axes
     #[serde(with = "fixedbitset")]
     pub rel_bits: FixedBitSet,
-    /// Supported event types (right now keys and rel)
+    /// Available absolute axes
+    #[serde(with = "fixedbitset")]
+    pub abs_bits: FixedBitSet,
+    /// Range, resolution and current value of each available absolute axis. The values of the
+    /// multitouch axes are the ones in the current slot.
+    pub abs_info: HashMap<u16, AbsInfo>,
+    /// The value in each slot, for each multitouch axis but `ABS_MT_SLOT`
+    pub mt_slots: HashMap<u16, Vec<i32>>,
+    /// Available LEDs
+    #[serde(with = "fixedbitset")]
+    pub led_bits: FixedBitSet,
+    /// Lit LEDs
+    #[serde(with = "fixedbitset")]
+    pub led_vals: FixedBitSet,
+    /// Available switches
+    #[serde(with = "fixedbitset")]
+    pub sw_bits: FixedBitSet,
+    /// Closed switches
+    #[serde(with = "fixedbitset")]
+    pub sw_vals: FixedBitSet,
+    /// Available misc events, e.g. `MSC_SCAN`
+    #[serde(with = "fixedbitset")]
+    pub msc_bits: FixedBitSet,
+    /// Available sounds
+    #[serde(with = "fixedbitset")]
+    pub snd_bits: FixedBitSet,
+    /// Key repeat delay and period, in ms, if the kernel repeats the keys of the device
+    pub repeat: Option<[u32; 2]>,
+    /// Supported force feedback effect types and parameters
+    #[serde(with = "fixedbitset")]
+    pub ff_bits: FixedBitSet,
+    /// How many force feedback effects can be uploaded at the same time
+    pub ff_effects_max: u32,
+    /// Device properties (`INPUT_PROP_*`)
+    #[serde(with = "fixedbitset")]
+    pub props: FixedBitSet,
+    /// Supported event types
     #[serde(with = "fixedbitset")]
     pub cap: FixedBitSet,
     /// Device name
@@ -45,6 +204,15 @@ pub struct InputDevice {
     /// Currently pressed keys
     #[serde(with = "fixedbitset")]
     pub key_vals: FixedBitSet,
+    /// Number of the last frame from the device included in this state, 0 if none is
+    pub frames: u64,
+    /// Bus type, `BUS_*`
+    pub bustype: u16,
+    /// Physical path, e.g. `usb-0000:00:14.0-1/input0`
+    pub phys: Option<String>,
+    /// Unique name, e.g. the serial number or the Bluetooth address. uinput can't set it, so it
+    /// isn't applied on the client.
+    pub uniq: Option<String>,
     /// VID
     pub vendor: u16,
     /// PID
@@ -53,7 +221,65 @@ pub struct InputDevice {
     pub version: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl InputDevice {
+    /// Update the state of the device with an event it generated
+    pub fn apply(&mut self, type_: u16, code: u16, value: i32) {
+        use crate::evdev::{AbsoluteAxis, Types};
+        if type_ == Types::KEY.number::<u16>() {
+            if (code as usize) < self.key_vals.len() {
+                // Auto-repeat (2) counts as pressed
+                self.key_vals.set(code as usize, value != 0);
+            }
+            return;
+        }
+        if type_ == Types::LED.number::<u16>() {
+            if (code as usize) < self.led_vals.len() {
+                self.led_vals.set(code as usize, value != 0);
+            }
+            return;
+        }
+        if type_ == Types::SWITCH.number::<u16>() {
+            if (code as usize) < self.sw_vals.len() {
+                self.sw_vals.set(code as usize, value != 0);
+            }
+            return;
+        }
+        if type_ == Types::REPEAT.number::<u16>() {
+            if let Some(val) = self.repeat.as_mut().and_then(|r| r.get_mut(code as usize)) {
+                *val = value as u32;
+            }
+            return;
+        }
+        if type_ != Types::ABSOLUTE.number::<u16>() {
+            return;
+        }
+        let slot = self
+            .abs_info
+            .get(&AbsoluteAxis::ABS_MT_SLOT.number())
+            .map(|info| info.value as usize);
+        match (self.mt_slots.get_mut(&code), slot) {
+            (Some(vals), Some(slot)) if slot < vals.len() => vals[slot] = value,
+            _ => {
+                if let Some(info) = self.abs_info.get_mut(&code) {
+                    info.value = value;
+                }
+            }
+        }
+    }
+}
+
+/// Same as `struct input_absinfo`
+#[derive(PartialEq, Eq, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InputEvent {
     pub type_: u16,
     pub code: u16,
@@ -86,3 +312,101 @@ mod fixedbitset {
         Ok(ret)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{AbsInfo, InputDevice};
+    use ::fixedbitset::FixedBitSet;
+
+    pub(crate) const EV_KEY: u16 = 1;
+    pub(crate) const EV_ABS: u16 = 3;
+    pub(crate) const KEY_A: u16 = 30;
+    pub(crate) const ABS_X: u16 = 0x00;
+    pub(crate) const ABS_MT_SLOT: u16 = 0x2f;
+    pub(crate) const ABS_MT_POSITION_X: u16 = 0x35;
+    pub(crate) const ABS_MT_TRACKING_ID: u16 = 0x39;
+    pub(crate) const SYN: (u16, u16, i32) = (0, 0, 0);
+
+    /// A touchpad with a button and two slots, nothing pressed or touched
+    pub(crate) fn touchpad() -> InputDevice {
+        let info = AbsInfo {
+            value: 0,
+            minimum: 0,
+            maximum: 1000,
+            fuzz: 0,
+            flat: 0,
+            resolution: 0,
+        };
+        let axes = [ABS_X, ABS_MT_SLOT, ABS_MT_POSITION_X, ABS_MT_TRACKING_ID];
+        let mut abs_bits = FixedBitSet::with_capacity(0x40);
+        abs_bits.extend(axes.iter().map(|code| *code as usize));
+        let mut key_bits = FixedBitSet::with_capacity(0x300);
+        key_bits.insert(KEY_A as usize);
+        let mut cap = FixedBitSet::with_capacity(0x20);
+        cap.extend([EV_KEY as usize, EV_ABS as usize]);
+        InputDevice {
+            key_bits,
+            rel_bits: FixedBitSet::with_capacity(0x10),
+            abs_bits,
+            abs_info: axes.iter().map(|code| (*code, info)).collect(),
+            mt_slots: [
+                (ABS_MT_POSITION_X, vec![0; 2]),
+                (ABS_MT_TRACKING_ID, vec![-1; 2]),
+            ]
+            .iter()
+            .cloned()
+            .collect(),
+            led_bits: FixedBitSet::with_capacity(0x10),
+            led_vals: FixedBitSet::with_capacity(0x10),
+            sw_bits: FixedBitSet::with_capacity(0x10),
+            sw_vals: FixedBitSet::with_capacity(0x10),
+            msc_bits: FixedBitSet::with_capacity(0x08),
+            snd_bits: FixedBitSet::with_capacity(0x08),
+            repeat: None,
+            ff_bits: FixedBitSet::with_capacity(0x80),
+            ff_effects_max: 0,
+            props: FixedBitSet::with_capacity(0x20),
+            cap,
+            name: "touchpad".to_owned(),
+            key_vals: FixedBitSet::with_capacity(0x300),
+            frames: 0,
+            bustype: 0,
+            phys: None,
+            uniq: None,
+            vendor: 0,
+            product: 0,
+            version: 0,
+        }
+    }
+
+    #[test]
+    fn test_apply_keys() {
+        let mut state = touchpad();
+        state.apply(EV_KEY, KEY_A, 1);
+        assert!(state.key_vals.contains(KEY_A as usize));
+        // Auto-repeat
+        state.apply(EV_KEY, KEY_A, 2);
+        assert!(state.key_vals.contains(KEY_A as usize));
+        state.apply(EV_KEY, KEY_A, 0);
+        assert!(!state.key_vals.contains(KEY_A as usize));
+    }
+
+    #[test]
+    fn test_apply_slots() {
+        let mut state = touchpad();
+        state.apply(EV_ABS, ABS_MT_SLOT, 1);
+        state.apply(EV_ABS, ABS_MT_TRACKING_ID, 7);
+        state.apply(EV_ABS, ABS_MT_POSITION_X, 100);
+        state.apply(EV_ABS, ABS_X, 100);
+        assert_eq!(state.abs_info[&ABS_MT_SLOT].value, 1);
+        assert_eq!(state.mt_slots[&ABS_MT_TRACKING_ID], vec![-1, 7]);
+        assert_eq!(state.mt_slots[&ABS_MT_POSITION_X], vec![0, 100]);
+        assert_eq!(state.abs_info[&ABS_X].value, 100);
+
+        // Back to the first slot, the second one keeps its values
+        state.apply(EV_ABS, ABS_MT_SLOT, 0);
+        state.apply(EV_ABS, ABS_MT_POSITION_X, 50);
+        assert_eq!(state.mt_slots[&ABS_MT_POSITION_X], vec![50, 100]);
+        assert_eq!(state.mt_slots[&ABS_MT_TRACKING_ID], vec![-1, 7]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Noticing when the configuration should be reloaded.
+use ::anyhow::Result;
+use ::log::{debug, error};
+use ::std::path::{Path, PathBuf};
+
+/// Call `notify` whenever we receive SIGHUP.
+fn watch_sighup(notify: impl Fn() + Send + 'static) -> Result<()> {
+    let mut signals = ::signal_hook::iterator::Signals::new([::signal_hook::consts::SIGHUP])?;
+    ::std::thread::spawn(move || {
+        for _ in signals.forever() {
+            debug!("Got SIGHUP");
+            notify();
+        }
+    });
+    Ok(())
+}
+
+/// Call `notify` whenever the config file at `path`, or anything in its drop-in directory, has
+/// changed. Blocks forever.
+fn watch_files(path: &Path, notify: impl Fn()) -> Result<!> {
+    use ::nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_MOVED_FROM
+        | AddWatchFlags::IN_CREATE
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_MOVE_SELF;
+    let inotify = Inotify::init(InitFlags::empty())?;
+    // Editors tend to replace files instead of writing to them, so watch the directories instead
+    // of the files themselves.
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name();
+    let dropin_dir = ::config::dropin_dir(path);
+    let dropin_dir_name = dropin_dir.file_name();
+    let dir_wd = inotify.add_watch(dir, flags)?;
+    let mut dropin_wd = inotify.add_watch(&dropin_dir, flags).ok();
+    loop {
+        let mut changed = false;
+        for event in inotify.read_events()? {
+            if event.wd == dir_wd {
+                let name = event.name.as_deref();
+                if name == dropin_dir_name {
+                    // The drop-in directory might have just been created
+                    dropin_wd = dropin_wd.or_else(|| inotify.add_watch(&dropin_dir, flags).ok());
+                    changed = true;
+                } else if name == file_name {
+                    changed = true;
+                }
+            } else if Some(event.wd) == dropin_wd {
+                // The drop-in directory is gone, watch it again once it's recreated
+                if event.mask.contains(AddWatchFlags::IN_MOVE_SELF) {
+                    let _ = inotify.rm_watch(event.wd);
+                    dropin_wd = None;
+                } else if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    dropin_wd = None;
+                }
+                changed = true;
+            }
+        }
+        if changed {
+            debug!("Config files changed");
+            notify();
+        }
+    }
+}
+
+/// Call `notify` whenever the config should be reloaded: on SIGHUP, or when the config file at
+/// `path` or its drop-ins change.
+pub(crate) fn watch_config(
+    path: PathBuf,
+    notify: impl Fn() + Clone + Send + 'static,
+) -> Result<()> {
+    watch_sighup(notify.clone())?;
+    ::std::thread::spawn(move || {
+        let Err(e) = watch_files(&path, notify);
+        error!("Stopped watching config files: {}", e);
+    });
+    Ok(())
+}
@@ -0,0 +1,197 @@
+//! Replacing our key pair, and telling our peers about the new key.
+use crate::proto::{ClientMessage, KeyRotation, ServerMessage};
+use ::anyhow::{anyhow, Context, Result};
+use ::async_std::net::UdpSocket;
+use ::cdgram::CDGramClient;
+use ::config::{Config, SecretStorage};
+use ::log::{debug, info};
+use ::sodiumoxide::crypto::box_::{self, PublicKey, SecretKey, PUBLICKEYBYTES};
+use ::std::path::Path;
+use ::std::time::Duration;
+
+/// How long the old key is accepted after a rotation, if not specified otherwise
+pub(crate) const DEFAULT_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+impl KeyRotation {
+    /// Announce `new_pk` to the peer with key `peer_pk`, signed with our current secret key.
+    pub(crate) fn new(
+        new_pk: &PublicKey,
+        grace: Duration,
+        peer_pk: &PublicKey,
+        old_sk: &SecretKey,
+    ) -> Self {
+        let mut msg = new_pk.as_ref().to_vec();
+        msg.extend(&grace.as_secs().to_le_bytes());
+        let nonce = box_::gen_nonce();
+        Self {
+            nonce: nonce.as_ref().to_vec(),
+            sealed: box_::seal(&msg, &nonce, peer_pk, old_sk),
+        }
+    }
+    /// Verify this announcement came from `sender_pk`, and return the new key and grace period.
+    pub(crate) fn open(
+        &self,
+        sender_pk: &PublicKey,
+        our_sk: &SecretKey,
+    ) -> Result<(PublicKey, Duration)> {
+        let nonce = box_::Nonce::from_slice(&self.nonce)
+            .with_context(|| "Malformed key rotation".to_owned())?;
+        let msg = box_::open(&self.sealed, &nonce, sender_pk, our_sk)
+            .map_err(|()| anyhow!("Key rotation isn't signed by the peer"))?;
+        if msg.len() != PUBLICKEYBYTES + 8 {
+            return Err(anyhow!("Malformed key rotation"));
+        }
+        let mut grace = [0; 8];
+        grace.copy_from_slice(&msg[PUBLICKEYBYTES..]);
+        Ok((
+            PublicKey::from_slice(&msg[..PUBLICKEYBYTES]).unwrap(),
+            Duration::from_secs(u64::from_le_bytes(grace)),
+        ))
+    }
+}
+
+/// Record in the config file at `path` that the peer using key `old` now uses key `new`.
+fn record_key_rotation(
+    path: &Path,
+    old: &PublicKey,
+    new: PublicKey,
+    grace: Duration,
+) -> Result<()> {
+    let mut cfg = Config::read(path)?;
+    let peer = cfg
+        .peers
+        .iter_mut()
+        .find(|p| p.public_keys().any(|k| k == *old))
+        .with_context(|| "Key rotation from an unknown peer".to_owned())?;
+    if peer.public() == new {
+        // Already recorded, the acknowledgement must have been lost
+        return Ok(());
+    }
+    if let Some(origin) = peer.origin() {
+        return Err(anyhow!(
+            "The peer is defined in {}, its key has to be updated there",
+            origin.display()
+        ));
+    }
+    peer.rotate_key(new, grace);
+    cfg.save(path)
+}
+
+/// Handle a key rotation announced by the peer using key `sender`, recording the new key in the
+/// config file at `path`.
+pub(crate) async fn accept_key_rotation(
+    sender: PublicKey,
+    rotation: &KeyRotation,
+    our_sk: &SecretKey,
+    path: Option<&Path>,
+) -> Result<()> {
+    let (new, grace) = rotation.open(&sender, our_sk)?;
+    let path = path
+        .with_context(|| "Config isn't from a file, can't record the new key".to_owned())?
+        .to_owned();
+    info!(
+        "Peer {} rotated its key to {}",
+        ::base64::encode(sender),
+        ::base64::encode(new)
+    );
+    ::async_std::task::spawn_blocking(move || record_key_rotation(&path, &sender, new, grace)).await
+}
+
+/// Tell the server at `addr`, with key `server_pk`, about our new key.
+async fn announce(
+    old_pk: PublicKey,
+    old_sk: SecretKey,
+    server_pk: PublicKey,
+    addr: ::std::net::SocketAddr,
+    rotation: &KeyRotation,
+) -> Result<()> {
+    use ::async_std::future::timeout;
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let mut client = CDGramClient::new(old_pk, old_sk, server_pk, socket);
+    timeout(Duration::from_secs(1), client.connect(addr))
+        .await
+        .map_err(|_| anyhow!("Timed out connecting"))??;
+    let msg = ::bincode::serialize(&ClientMessage::KeyRotation(rotation.clone()))?;
+    for _ in 0..3 {
+        client.send(&msg).await?;
+        if let Ok(reply) = timeout(Duration::from_secs(1), client.recv()).await {
+            if let ServerMessage::KeyRotated = ::bincode::deserialize(&reply?)? {
+                return Ok(());
+            }
+        }
+    }
+    Err(anyhow!("No acknowledgement"))
+}
+
+/// Generate a new key pair, and tell every reachable server peer about it. Peers without an
+/// address are told by the running server daemon, if they are connected to it.
+pub(crate) async fn run(path: &Path, opts: super::EntangledRotateKeyOpts) -> Result<()> {
+    let passphrase = match Config::read(path)?.secret_storage() {
+        SecretStorage::Encrypted { .. } => Some(::config::passphrase_from_env_or_stdin()?),
+        _ => None,
+    };
+    let mut cfg = Config::load(path, || {
+        passphrase
+            .clone()
+            .with_context(|| "Missing passphrase".to_owned())
+    })?;
+    let grace = match opts.grace {
+        None => DEFAULT_GRACE,
+        Some(days) => days
+            .checked_mul(24 * 60 * 60)
+            .map(Duration::from_secs)
+            .filter(|grace| *grace <= ::config::MAX_GRACE)
+            .with_context(|| {
+                format!(
+                    "The grace period can't be longer than {} days",
+                    ::config::MAX_GRACE.as_secs() / (24 * 60 * 60)
+                )
+            })?,
+    };
+    let (old_pk, old_sk) = (cfg.public(), cfg.secret());
+    // The config goes first, the old secret key is only replaced once it has been saved
+    let staged = cfg.rotate(passphrase.as_deref(), grace)?;
+    cfg.save(path)?;
+    staged.commit()?;
+    let new_pk = cfg.public();
+    println!("New public key: {}", ::base64::encode(new_pk));
+
+    let mut unreachable = 0;
+    for peer in &cfg.peers {
+        let addr = match &peer.addr {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let resolved = {
+            let addr = addr.clone();
+            ::async_std::task::spawn_blocking(move || addr.resolve()).await
+        };
+        let mut told = false;
+        'peer: for server_pk in peer.public_keys() {
+            let rotation = KeyRotation::new(&new_pk, grace, &server_pk, &old_sk);
+            for resolved in resolved.iter().flatten() {
+                match announce(old_pk, old_sk.clone(), server_pk, *resolved, &rotation).await {
+                    Ok(()) => {
+                        told = true;
+                        break 'peer;
+                    }
+                    Err(e) => debug!("Failed to tell {} at {}: {}", addr, resolved, e),
+                }
+            }
+        }
+        if told {
+            println!("Told {} about the new key", addr);
+        } else {
+            println!("Couldn't reach {}", addr);
+            unreachable += 1;
+        }
+    }
+    if unreachable > 0 {
+        println!(
+            "{} peer(s) couldn't be reached, they have to be paired again once the old key \
+             stops being accepted",
+            unreachable
+        );
+    }
+    Ok(())
+}
@@ -1,36 +1,155 @@
-use ::std::collections::{HashMap, HashSet};
+use ::std::collections::{hash_map::Entry, HashMap, HashSet};
 
-use crate::proto::{ClientMessage, InputDevice, ServerMessage};
-use ::anyhow::Result;
+use crate::proto::{ClientMessage, DeviceOutput, InputDevice, ServerMessage};
+use ::anyhow::{Context, Result};
 use ::async_std::net::{SocketAddr, UdpSocket};
+use ::sodiumoxide::crypto::box_::PublicKey;
+use ::std::path::PathBuf;
+use ::std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::evdev;
 use ::async_std::sync::{Arc, Mutex};
 use ::cdgram::CDGramServer;
-use ::log::{debug, info, trace};
+use ::fixedbitset::FixedBitSet;
+use ::log::{debug, error, info, trace, warn};
 
 #[derive(Clone, Debug)]
 enum Event {
     ClientPacket(ClientMessage),
-    InputEvent((u32, ::libc::input_event)),
+    /// A frame of events from a device
+    InputFrame((u32, u64, Vec<crate::proto::InputEvent>)),
     RemoveDevice(u32),
-    NewDevice((u32, InputDevice)),
+    NewDevice((u32, Box<InputDevice>)),
+    /// Time to send the clients which keys are pressed
+    KeyState,
 }
 
 #[derive(Debug)]
 enum ControlEvent {
     Event(Event),
-    MonitorNewDevice(evdev::Device),
+    MonitorNewDevice(Box<evdev::Device>),
     MonitorError(anyhow::Error),
     Timeout(SocketAddr),
+    /// The config should be reloaded
+    Reload,
+    /// Time to end the sessions of guests whose access has expired
+    Expire,
+    /// A client did something to a device
+    Output((SocketAddr, u32, DeviceOutput)),
+}
+
+/// How often the clients are told which keys are pressed
+const KEY_STATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames longer than this are sent in pieces, in case a device never ends them
+const MAX_FRAME_LEN: usize = 256;
+
+/// The task forwarding events from a device
+struct DeviceTask {
+    path: PathBuf,
+    task: async_std::task::JoinHandle<()>,
+    /// For devices with LEDs, sounds or force feedback
+    writer: Option<evdev::DeviceWriter>,
+    /// The ids of the force feedback effects the clients uploaded, by the client and the id
+    /// on the client
+    effects: HashMap<(SocketAddr, i16), i16>,
+}
+
+impl DeviceTask {
+    /// Do `output`, from the client at `addr`, to the device
+    fn output(&mut self, addr: SocketAddr, output: DeviceOutput) -> Result<()> {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        match output {
+            DeviceOutput::Leds(lit) => writer.set_leds(&lit)?,
+            DeviceOutput::Sound { code, value } => writer.play_sound(code, value)?,
+            DeviceOutput::UploadEffect(effect) => {
+                let id = self.effects.get(&(addr, effect.id)).copied();
+                let mut raw = effect
+                    .to_raw(id.unwrap_or(-1))
+                    .map_err(::anyhow::Error::msg)?;
+                let id = writer.upload_effect(&mut raw)?;
+                self.effects.insert((addr, effect.id), id);
+            }
+            DeviceOutput::EraseEffect(id) => {
+                if let Some(id) = self.effects.remove(&(addr, id)) {
+                    writer.erase_effect(id)?;
+                }
+            }
+            DeviceOutput::ForceFeedback { code, value } => {
+                if code < evdev::FF_GAIN as u16 {
+                    // Playing or stopping an effect
+                    if let Some(id) = self.effects.get(&(addr, code as i16)) {
+                        writer.write_ff(*id as u16, value)?;
+                    }
+                } else {
+                    writer.write_ff(code, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase the force feedback effects the client at `addr` uploaded
+    fn forget_client(&mut self, addr: SocketAddr) {
+        let ids: Vec<_> = self
+            .effects
+            .keys()
+            .filter(|k| k.0 == addr)
+            .copied()
+            .collect();
+        for key in ids {
+            let id = self.effects.remove(&key).unwrap();
+            if let Some(writer) = &self.writer {
+                writer
+                    .erase_effect(id)
+                    .unwrap_or_else(|e| debug!("Failed to erase effect {}: {}", id, e));
+            }
+        }
+    }
 }
 
 struct ClientStates {
     synced_devices: HashSet<u32>,
     addr: SocketAddr,
     timeout: Option<async_std::task::JoinHandle<()>>,
+    /// Which devices the client can use, if it's a guest restricted to some of them
+    devices: ::config::DevicePolicy,
 }
 impl ClientStates {
+    /// Restrict the client to the devices `policy` allows, and tell it to drop the devices it
+    /// can no longer use, and about the ones it now can.
+    fn restrict(
+        &mut self,
+        policy: ::config::DevicePolicy,
+        devices: &HashMap<u32, InputDevice>,
+    ) -> Option<ServerMessage> {
+        if policy == self.devices {
+            return None;
+        }
+        self.devices = policy;
+        let mut updates = HashMap::new();
+        for (id, dev) in devices {
+            if !self.devices.allows(&dev.name) {
+                if self.synced_devices.remove(id) {
+                    updates.insert(*id, crate::proto::InputDeviceUpdate::Drop);
+                }
+            } else if self.synced_devices.insert(*id) {
+                updates.insert(
+                    *id,
+                    crate::proto::InputDeviceUpdate::Update(Box::new(dev.clone())),
+                );
+            }
+        }
+        if updates.is_empty() {
+            None
+        } else {
+            Some(ServerMessage::Sync(updates))
+        }
+    }
+
     async fn handle_event(
         &mut self,
         event: &Event,
@@ -45,7 +164,7 @@ impl ClientStates {
             Event::ClientPacket(ClientMessage::Sync(devs)) => {
                 let mut updates = HashMap::new();
                 for (id, dev) in devs {
-                    match devices.get(id) {
+                    match devices.get(id).filter(|e| self.devices.allows(&e.name)) {
                         None => {
                             debug!("Telling client {} to drop {}:{}", self.addr, id, dev.name);
                             updates
@@ -61,7 +180,9 @@ impl ClientStates {
                                 updates
                                     .insert(
                                         *id,
-                                        crate::proto::InputDeviceUpdate::Update(dev.clone()),
+                                        crate::proto::InputDeviceUpdate::Update(Box::new(
+                                            dev.clone(),
+                                        )),
                                     )
                                     .unwrap_none();
                             }
@@ -69,16 +190,18 @@ impl ClientStates {
                     }
                 }
                 // Inform the client all the devices it didn't know
-                for (id, dev) in devices {
+                let policy = &self.devices;
+                let allowed = devices.iter().filter(|(_, dev)| policy.allows(&dev.name));
+                for (id, dev) in allowed.clone() {
                     debug!(
                         "Sending new device {}:{} to client {}",
                         id, dev.name, self.addr
                     );
-                    updates
-                        .entry(*id)
-                        .or_insert_with(|| crate::proto::InputDeviceUpdate::Update(dev.clone()));
+                    updates.entry(*id).or_insert_with(|| {
+                        crate::proto::InputDeviceUpdate::Update(Box::new(dev.clone()))
+                    });
                 }
-                self.synced_devices = devices.keys().copied().collect();
+                self.synced_devices = allowed.map(|(id, _)| *id).collect();
                 Some(ServerMessage::Sync(updates))
             }
             Event::ClientPacket(ClientMessage::KeepAlive) => {
@@ -86,18 +209,26 @@ impl ClientStates {
                 None
             }
             Event::ClientPacket(ClientMessage::Ping) => Some(ServerMessage::Pong),
+            // Handled before it gets here, they need the key of the client
+            Event::ClientPacket(ClientMessage::KeyRotation(_))
+            | Event::ClientPacket(ClientMessage::Unpaired) => None,
+            // Handled before it gets here, they go to the device
+            Event::ClientPacket(ClientMessage::Output { .. }) => None,
             Event::RemoveDevice(dev_id) => {
                 debug!("Telling client {} to drop {}", self.addr, dev_id);
+                self.synced_devices.remove(dev_id);
                 use ::std::iter::once;
                 Some(ServerMessage::Sync(
                     once((*dev_id, crate::proto::InputDeviceUpdate::Drop)).collect(),
                 ))
             }
+            Event::NewDevice((_, dev)) if !self.devices.allows(&dev.name) => None,
             Event::NewDevice((dev_id, dev)) => {
                 debug!(
                     "Sending new device {}:{} to client {}",
                     dev_id, dev.name, self.addr
                 );
+                self.synced_devices.insert(*dev_id);
                 use ::std::iter::once;
                 Some(ServerMessage::Sync(
                     once((
@@ -107,19 +238,28 @@ impl ClientStates {
                     .collect(),
                 ))
             }
-            Event::InputEvent((dev_id, ev)) => {
+            Event::KeyState => {
+                let key = evdev::Types::KEY.number();
+                let state = devices
+                    .iter()
+                    .filter(|(id, dev)| self.synced_devices.contains(id) && dev.cap.contains(key))
+                    .map(|(id, dev)| {
+                        let pressed = dev.key_vals.ones().map(|k| k as u16).collect();
+                        (*id, (dev.frames, pressed))
+                    })
+                    .collect::<HashMap<_, _>>();
+                if state.is_empty() {
+                    None
+                } else {
+                    Some(ServerMessage::KeyState(state))
+                }
+            }
+            Event::InputFrame((dev_id, n, frame)) => {
                 if !self.synced_devices.contains(&dev_id) {
                     None
                 } else {
                     trace!("Input from {} to client {}", dev_id, self.addr);
-                    Some(ServerMessage::Event((
-                        *dev_id,
-                        crate::proto::InputEvent {
-                            type_: ev.type_,
-                            code: ev.code,
-                            value: ev.value,
-                        },
-                    )))
+                    Some(ServerMessage::Frame((*dev_id, *n, frame.clone())))
                 }
             }
         }
@@ -130,21 +270,85 @@ fn start_device(
     id: u32,
     mut dev: evdev::Device,
     device_tx: ::async_std::channel::Sender<ControlEvent>,
-) {
-    ::async_std::task::spawn(async move {
+) -> DeviceTask {
+    let path = dev.path().to_owned();
+    let writer = if dev.leds_supported().is_empty()
+        && dev.sounds_supported().is_empty()
+        && dev.ff_effects_max() == 0
+    {
+        None
+    } else {
+        dev.writer()
+            .map_err(|e| warn!("Can't write to {}: {}", path.display(), e))
+            .ok()
+    };
+    let task = ::async_std::task::spawn(async move {
         debug!("Device task for dev_id {} started", id);
-        while let Ok(event) = dev.next_event().await {
+        let mut frame = Vec::new();
+        let mut frames = 0;
+        while let Ok(event) = dev.next_synced_event().await {
             debug!("Got event from dev_id {}", id);
-            device_tx
-                .send(ControlEvent::Event(Event::InputEvent((id as u32, event))))
-                .await
-                .unwrap();
+            let end = event.type_ == evdev::Types::SYNCHRONIZATION.number::<u16>()
+                && event.code == evdev::SYN_REPORT as u16;
+            frame.push(crate::proto::InputEvent {
+                type_: event.type_,
+                code: event.code,
+                value: event.value,
+            });
+            if end || frame.len() >= MAX_FRAME_LEN {
+                let frame = ::std::mem::take(&mut frame);
+                frames += 1;
+                device_tx
+                    .send(ControlEvent::Event(Event::InputFrame((
+                        id as u32, frames, frame,
+                    ))))
+                    .await
+                    .unwrap();
+            }
         }
         device_tx
             .send(ControlEvent::Event(Event::RemoveDevice(id as u32)))
             .await
             .unwrap();
     });
+    DeviceTask {
+        path,
+        task,
+        writer,
+        effects: HashMap::new(),
+    }
+}
+
+/// Stop forwarding the devices `policy` no longer allows, and send every device we can find
+/// through `device_tx`, so the ones `policy` newly allows can be picked up.
+async fn apply_device_policy(
+    policy: &::config::DevicePolicy,
+    devices: &Mutex<HashMap<u32, InputDevice>>,
+    device_tasks: &mut HashMap<u32, DeviceTask>,
+    device_tx: &::async_std::channel::Sender<ControlEvent>,
+) -> Result<()> {
+    let denied: Vec<_> = devices
+        .lock()
+        .await
+        .iter()
+        .filter(|(_, dev)| !policy.allows(&dev.name))
+        .map(|(id, _)| *id)
+        .collect();
+    for id in denied {
+        info!("Device {} is no longer allowed", id);
+        if let Some(device_task) = device_tasks.remove(&id) {
+            device_task.task.cancel().await;
+        }
+        device_tx
+            .send(ControlEvent::Event(Event::RemoveDevice(id)))
+            .await?;
+    }
+    for dev in evdev::enumerate().await? {
+        device_tx
+            .send(ControlEvent::MonitorNewDevice(Box::new(dev)))
+            .await?;
+    }
+    Ok(())
 }
 
 fn monitor_devices(device_tx: ::async_std::channel::Sender<ControlEvent>) -> Result<!> {
@@ -169,7 +373,7 @@ fn monitor_devices(device_tx: ::async_std::channel::Sender<ControlEvent>) -> Res
                 ::async_std::task::spawn(async move {
                     let dev = evdev::Device::open(&path).await?;
                     device_tx
-                        .send(ControlEvent::MonitorNewDevice(dev))
+                        .send(ControlEvent::MonitorNewDevice(Box::new(dev)))
                         .await
                         .unwrap();
                     Result::<_, ::anyhow::Error>::Ok(())
@@ -189,12 +393,55 @@ fn get_device_state((id, dev): (u32, &evdev::Device)) -> Result<(u32, InputDevic
         dev.events_supported().bits()
     );
     let input_id = dev.input_id();
+    let abs_bits: FixedBitSet = dev.absolute_axes_supported().into();
+    let abs_info = abs_bits
+        .ones()
+        .map(|code| {
+            let info = &dev.state().abs_vals[code];
+            let info = crate::proto::AbsInfo {
+                value: info.value,
+                minimum: info.minimum,
+                maximum: info.maximum,
+                fuzz: info.fuzz,
+                flat: info.flat,
+                resolution: info.resolution,
+            };
+            (code as u16, info)
+        })
+        .collect();
     let state = InputDevice {
         name: dev.name().to_str()?.to_owned(),
         key_bits: dev.keys_supported().clone(),
         rel_bits: dev.relative_axes_supported().into(),
+        abs_bits,
+        abs_info,
+        mt_slots: dev.state().mt_vals.clone(),
+        led_bits: dev.leds_supported().into(),
+        led_vals: dev.state().led_vals.clone(),
+        sw_bits: dev.switches_supported().into(),
+        sw_vals: dev.state().switch_vals.clone(),
+        msc_bits: dev.misc_properties().into(),
+        snd_bits: dev.sounds_supported().into(),
+        repeat: if dev.events_supported().contains(evdev::Types::REPEAT) {
+            Some(dev.state().rep_vals)
+        } else {
+            None
+        },
+        ff_bits: dev.ff_supported().clone(),
+        ff_effects_max: dev.ff_effects_max(),
+        props: dev.properties().into(),
         cap: dev.events_supported().into(),
         key_vals: dev.state().key_vals.clone(),
+        frames: 0,
+        bustype: input_id.bustype,
+        phys: dev
+            .physical_path()
+            .as_ref()
+            .map(|p| p.to_string_lossy().into_owned()),
+        uniq: dev
+            .unique_name()
+            .as_ref()
+            .map(|u| u.to_string_lossy().into_owned()),
         product: input_id.product,
         vendor: input_id.vendor,
         version: input_id.version,
@@ -202,30 +449,132 @@ fn get_device_state((id, dev): (u32, &evdev::Device)) -> Result<(u32, InputDevic
     Result::Ok((id as u32, state))
 }
 
-pub(crate) async fn run(global_cfg: ::config::Config, _: super::EntangledServerOpts) -> Result<!> {
-    let socket = UdpSocket::bind(("0.0.0.0", 3241)).await?;
+/// The keys of all the peers, with the time they stop being allowed, for `CDGramServer`
+fn authorized_keys(cfg: &::config::Config) -> Vec<(PublicKey, Option<SystemTime>)> {
+    cfg.peers
+        .iter()
+        .flat_map(|p| p.authorized_keys())
+        .map(|(pk, expires)| (pk, expires.map(|e| UNIX_EPOCH + Duration::from_secs(e))))
+        .collect()
+}
+
+/// The devices each guest that is restricted to some devices can use, by its keys
+fn guest_devices(cfg: &::config::Config) -> HashMap<PublicKey, ::config::DevicePolicy> {
+    cfg.peers
+        .iter()
+        .filter_map(|p| Some((p, p.devices.as_ref()?)))
+        .flat_map(|(p, devices)| p.public_keys().map(move |pk| (pk, devices.clone())))
+        .collect()
+}
+
+/// Forget about the clients at `closed`, whose sessions have been closed, and the force feedback
+/// effects they uploaded
+async fn drop_clients(
+    active_clients: &Mutex<HashMap<SocketAddr, ClientStates>>,
+    device_tasks: &mut HashMap<u32, DeviceTask>,
+    closed: Vec<SocketAddr>,
+) {
+    let mut active_clients = active_clients.lock().await;
+    for addr in closed {
+        for device_task in device_tasks.values_mut() {
+            device_task.forget_client(addr);
+        }
+        if let Some(mut g) = active_clients.remove(&addr) {
+            if let Some(timeout) = g.timeout.take() {
+                timeout.cancel().await;
+            }
+        }
+    }
+}
+
+/// Forget about the client at `addr`, whose session has timed out. It might have been dropped by
+/// `drop_clients` already, while the timeout was waiting to be handled.
+async fn time_out_client<T: ::cdgram::Socket>(
+    server: &CDGramServer<T>,
+    active_clients: &Mutex<HashMap<SocketAddr, ClientStates>>,
+    device_tasks: &mut HashMap<u32, DeviceTask>,
+    addr: SocketAddr,
+) {
+    if let Ok(true) = server.close(addr).await {
+        info!("Connection to {} has timed out, dropping it", addr);
+    }
+    for device_task in device_tasks.values_mut() {
+        device_task.forget_client(addr);
+    }
+    if let Some(mut g) = active_clients.lock().await.remove(&addr) {
+        // Note: g.timeout is not necessarily the timeout task that sent us this Timeout
+        // message. It could be: timeout -> new message sent -> new timeout task replaced
+        // the old one -> we receive the Timeout message. In this case the new timeout
+        // might still fire, so we need to cancel it.
+        if let Some(timeout) = g.timeout.take() {
+            timeout.cancel().await;
+        }
+    }
+}
+
+pub(crate) async fn run(
+    mut global_cfg: ::config::Config,
+    opts: super::EntangledServerOpts,
+) -> Result<!> {
+    let socket = UdpSocket::bind(("0.0.0.0", global_cfg.port()))
+        .await
+        .with_context(|| format!("Failed to listen on port {}", global_cfg.port()))?;
+    let socket = Arc::new(socket);
+    let (pairing_tx, pairing_rx) = ::async_std::channel::bounded(16);
+    let _announcer = if opts.announce {
+        Some(::discovery::announce(&::discovery::Beacon {
+            kind: ::discovery::Kind::Server,
+            name: global_cfg.name().unwrap_or_default(),
+            port: socket.local_addr()?.port(),
+            public: global_cfg.public().as_ref().to_vec(),
+        })?)
+    } else {
+        None
+    };
     let server = Arc::new(CDGramServer::new(
         global_cfg.public(),
         global_cfg.secret(),
-        global_cfg.peers.iter().map(|p| p.public()),
-        socket,
+        authorized_keys(&global_cfg),
+        crate::pairing::Demux::new(socket.clone(), pairing_tx),
     ));
+    if let Some(path) = global_cfg.path() {
+        ::async_std::task::spawn(crate::pairing::run(
+            socket,
+            pairing_rx,
+            server.clone(),
+            path.to_owned(),
+        ));
+    }
 
     let active_clients = Arc::new(Mutex::new(HashMap::new()));
+    let guests = Arc::new(Mutex::new(guest_devices(&global_cfg)));
     let (device_tx, device_rx) = ::async_std::channel::unbounded();
     // This function starts a new thread to handle the events from a device.
     // Received events will be sent through device_tx
-    let devices: HashMap<_, _> = evdev::enumerate()
-        .await?
-        .into_iter()
-        .enumerate()
-        .map(|(id, dev)| {
-            let (id, ret) = get_device_state((id as u32, &dev))?;
-            debug!("Creating device {}:{}, {:?}", id, ret.name, ret);
-            start_device(id as u32, dev, device_tx.clone());
-            Ok((id, ret))
-        })
-        .collect::<Result<HashMap<_, _>>>()?;
+    let mut devices = HashMap::new();
+    let mut device_tasks = HashMap::new();
+    let mut next_dev_id = 0;
+    for dev in evdev::enumerate().await? {
+        if !global_cfg.devices.allows(&dev.name().to_string_lossy()) {
+            debug!("Skipping device {:?}", dev.name());
+            continue;
+        }
+        let (id, ret) = get_device_state((next_dev_id, &dev))?;
+        next_dev_id += 1;
+        debug!("Creating device {}:{}, {:?}", id, ret.name, ret);
+        device_tasks.insert(id, start_device(id, dev, device_tx.clone()));
+        devices.insert(id, ret);
+    }
+
+    if let Some(path) = global_cfg.path() {
+        // Left over from unpairing while no server was running, those clients aren't connected
+        crate::unpair::take_notices(path);
+        let device_tx = device_tx.clone();
+        crate::reload::watch_config(path.to_owned(), move || {
+            // Never fails, the channel is unbounded and we never close it
+            let _ = device_tx.try_send(ControlEvent::Reload);
+        })?;
+    }
 
     // Lock order, active_clients > devices
     let devices = Arc::new(Mutex::new(devices));
@@ -237,22 +586,107 @@ pub(crate) async fn run(global_cfg: ::config::Config, _: super::EntangledServerO
         ::async_std::task::block_on(device_tx2.send(ControlEvent::MonitorError(e))).unwrap();
     });
 
+    let expire_tx = device_tx.clone();
+    ::async_std::task::spawn(async move {
+        loop {
+            ::async_std::task::sleep(Duration::from_secs(1)).await;
+            if expire_tx.send(ControlEvent::Expire).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let key_state_tx = device_tx.clone();
+    ::async_std::task::spawn(async move {
+        loop {
+            ::async_std::task::sleep(KEY_STATE_INTERVAL).await;
+            let event = ControlEvent::Event(Event::KeyState);
+            if key_state_tx.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
     let active_clients2 = active_clients.clone();
+    let guests2 = guests.clone();
     let server2 = server.clone();
     let device_tx3 = device_tx.clone();
+    let secret = global_cfg.secret();
+    let cfg_path = global_cfg.path().map(ToOwned::to_owned);
     let _: async_std::task::JoinHandle<Result<!>> = async_std::task::spawn(async move {
         loop {
             let msg = server.recv().await;
             let (addr, pkt) = msg?;
             let pkt = ::bincode::deserialize(&pkt)?;
+            if let ClientMessage::KeyRotation(rotation) = &pkt {
+                // Sent by `entangle rotate-key`, not by a client daemon. The new key gets picked
+                // up once the config file is reloaded.
+                let sender = match server.peer_key(addr).await {
+                    Some(sender) => sender,
+                    None => continue,
+                };
+                match crate::rotate::accept_key_rotation(
+                    sender,
+                    rotation,
+                    &secret,
+                    cfg_path.as_deref(),
+                )
+                .await
+                {
+                    Ok(()) => {
+                        let reply = ::bincode::serialize(&ServerMessage::KeyRotated)?;
+                        if let Err(e) = server.send(&addr, &reply).await {
+                            info!("Error: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept the new key of {}: {:#}", addr, e),
+                }
+                continue;
+            }
+            if let ClientMessage::Unpaired = pkt {
+                // Sent by `entangle unpair`. The session gets closed once the config file is
+                // reloaded without the client in it.
+                let sender = match server.peer_key(addr).await {
+                    Some(sender) => sender,
+                    None => continue,
+                };
+                match crate::unpair::accept_unpairing(sender, cfg_path.as_deref()).await {
+                    Ok(()) => {
+                        let reply = ::bincode::serialize(&ServerMessage::Unpaired)?;
+                        if let Err(e) = server.send(&addr, &reply).await {
+                            info!("Error: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to forget {}: {:#}", addr, e),
+                }
+                continue;
+            }
 
             let mut active_clients = active_clients2.lock().await;
-            let g = active_clients.entry(addr).or_insert_with(|| ClientStates {
-                synced_devices: HashSet::new(),
-                addr,
-                timeout: None,
-            });
+            let g = match active_clients.entry(addr) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let devices = match server.peer_key(addr).await {
+                        Some(pk) => guests2.lock().await.get(&pk).cloned(),
+                        None => None,
+                    };
+                    entry.insert(ClientStates {
+                        synced_devices: HashSet::new(),
+                        addr,
+                        timeout: None,
+                        devices: devices.unwrap_or_default(),
+                    })
+                }
+            };
             debug!("Got client packet {:?}", pkt);
+            if let ClientMessage::Output { device, output } = &pkt {
+                // Guests can only use the devices they can see
+                if g.synced_devices.contains(device) {
+                    device_tx3
+                        .send(ControlEvent::Output((addr, *device, output.clone())))
+                        .await?;
+                }
+            }
             if let Some(reply) = g
                 .handle_event(&Event::ClientPacket(pkt), &*devices.lock().await)
                 .await
@@ -284,33 +718,147 @@ pub(crate) async fn run(global_cfg: ::config::Config, _: super::EntangledServerO
         let event = match ctrl_msg {
             ControlEvent::Event(Event::RemoveDevice(id)) => {
                 debug!("Device {} has died", id);
-                // FIXME
-                devices2.lock().await.remove(&id).unwrap();
+                device_tasks.remove(&id);
+                if devices2.lock().await.remove(&id).is_none() {
+                    // Already removed
+                    continue;
+                }
                 Event::RemoveDevice(id)
             }
             ControlEvent::MonitorNewDevice(dev) => {
-                let dev_id = devices2.lock().await.len();
-                let (dev_id, state) = get_device_state((dev_id as u32, &dev))?;
+                let dev = *dev;
+                if !global_cfg.devices.allows(&dev.name().to_string_lossy()) {
+                    debug!("Skipping device {:?}", dev.name());
+                    continue;
+                }
+                if device_tasks.values().any(|t| t.path == dev.path()) {
+                    // We are already forwarding this device
+                    continue;
+                }
+                let (dev_id, state) = get_device_state((next_dev_id, &dev))?;
+                next_dev_id += 1;
                 devices2
                     .lock()
                     .await
                     .insert(dev_id, state.clone())
                     .unwrap_none();
-                start_device(dev_id, dev, device_tx.clone());
-                Event::NewDevice((dev_id as u32, state))
+                device_tasks.insert(dev_id, start_device(dev_id, dev, device_tx.clone()));
+                Event::NewDevice((dev_id as u32, Box::new(state)))
+            }
+            ControlEvent::Reload => {
+                info!("Reloading config");
+                let cfg = global_cfg.reload(|| {
+                    ::std::env::var("ENTANGLE_PASSPHRASE").map_err(|_| {
+                        ::anyhow::anyhow!("The secret key has changed, restart to load it")
+                    })
+                });
+                let cfg = match cfg {
+                    Ok(cfg) => cfg,
+                    Err(e) => {
+                        error!("Failed to reload config: {:#}", e);
+                        continue;
+                    }
+                };
+                if cfg.public() != global_cfg.public() {
+                    // Our key has been rotated, tell the connected clients about it
+                    for addr in active_clients.lock().await.keys() {
+                        let peer_pk = match server2.peer_key(*addr).await {
+                            Some(peer_pk) => peer_pk,
+                            None => continue,
+                        };
+                        let rotation = crate::proto::KeyRotation::new(
+                            &cfg.public(),
+                            cfg.rotation_grace(),
+                            &peer_pk,
+                            &global_cfg.secret(),
+                        );
+                        let msg = ::bincode::serialize(&ServerMessage::KeyRotation(rotation))?;
+                        if let Err(e) = server2.send(addr, &msg).await {
+                            info!("Error: {}", e);
+                        }
+                    }
+                    warn!("Our own key has changed, restart to start using it");
+                }
+                if cfg.port() != global_cfg.port() {
+                    warn!("The port has changed, restart to start listening on it");
+                }
+                // Tell the connected clients removed by `entangle unpair`, before closing their
+                // sessions. Clients removed in any other way aren't told.
+                let unpaired = global_cfg
+                    .path()
+                    .map(crate::unpair::take_notices)
+                    .unwrap_or_default();
+                let kept: HashSet<_> = cfg.peers.iter().flat_map(|p| p.public_keys()).collect();
+                for addr in active_clients.lock().await.keys() {
+                    let peer_pk = match server2.peer_key(*addr).await {
+                        Some(peer_pk) => peer_pk,
+                        None => continue,
+                    };
+                    if !unpaired.contains(&peer_pk) || kept.contains(&peer_pk) {
+                        continue;
+                    }
+                    let msg = ::bincode::serialize(&ServerMessage::Unpaired)?;
+                    if let Err(e) = server2.send(addr, &msg).await {
+                        info!("Error: {}", e);
+                    }
+                }
+                let closed = server2.set_authorized_keys(authorized_keys(&cfg)).await;
+                drop_clients(&active_clients, &mut device_tasks, closed).await;
+                let new_guests = guest_devices(&cfg);
+                for (addr, g) in active_clients.lock().await.iter_mut() {
+                    let devices = match server2.peer_key(*addr).await {
+                        Some(pk) => new_guests.get(&pk).cloned().unwrap_or_default(),
+                        None => continue,
+                    };
+                    if let Some(msg) = g.restrict(devices, &*devices2.lock().await) {
+                        if let Err(e) = server2.send(addr, &::bincode::serialize(&msg)?).await {
+                            info!("Error: {}", e);
+                        }
+                    }
+                }
+                *guests.lock().await = new_guests;
+                if cfg.devices != global_cfg.devices {
+                    let applied =
+                        apply_device_policy(&cfg.devices, &devices2, &mut device_tasks, &device_tx)
+                            .await;
+                    if let Err(e) = applied {
+                        error!("Failed to apply the new device policy: {:#}", e);
+                    }
+                }
+                global_cfg = cfg;
+                continue;
+            }
+            ControlEvent::Expire => {
+                let closed = server2.close_expired().await;
+                for addr in &closed {
+                    info!("The access of the guest at {} has expired", addr);
+                }
+                drop_clients(&active_clients, &mut device_tasks, closed).await;
+                continue;
+            }
+            ControlEvent::Output((addr, id, output)) => {
+                if let Some(device_task) = device_tasks.get_mut(&id) {
+                    if let Err(e) = device_task.output(addr, output) {
+                        warn!("Failed to write to device {}: {:#}", id, e);
+                    }
+                }
+                continue;
             }
             ControlEvent::MonitorError(e) => return Err(e),
-            ControlEvent::Event(e) => e,
+            ControlEvent::Event(e) => {
+                if let Event::InputFrame((id, n, frame)) = &e {
+                    // Keep the state up to date for clients that connect later
+                    if let Some(dev) = devices2.lock().await.get_mut(id) {
+                        for ev in frame {
+                            dev.apply(ev.type_, ev.code, ev.value);
+                        }
+                        dev.frames = *n;
+                    }
+                }
+                e
+            }
             ControlEvent::Timeout(addr) => {
-                // Remove the timed-out task
-                info!("Connection to {} has timed out, dropping it", addr);
-                server2.close(addr).await.unwrap();
-                let mut g = active_clients.lock().await.remove(&addr).unwrap();
-                // Note: g.timeout is not necessarily the timeout task that sent us this Timeout
-                // message. It could be: timeout -> new message sent -> new timeout task replaced
-                // the old one -> we receive the Timeout message. In this case the new timeout
-                // might still fire, so we need to cancel it.
-                g.timeout.take().unwrap().cancel().await;
+                time_out_client(&server2, &active_clients, &mut device_tasks, addr).await;
                 continue;
             }
         };
@@ -338,3 +886,72 @@ pub(crate) async fn run(global_cfg: ::config::Config, _: super::EntangledServerO
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client at `addr` that can use every device
+    fn client(addr: SocketAddr) -> ClientStates {
+        ClientStates {
+            synced_devices: HashSet::new(),
+            addr,
+            timeout: None,
+            devices: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_timeout_after_expire() {
+        ::async_std::task::block_on(async {
+            let (pk, sk) = ::sodiumoxide::crypto::box_::gen_keypair();
+            let socket = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+            let server = CDGramServer::new(pk, sk, Vec::new(), socket);
+            let addr: SocketAddr = "127.0.0.1:3241".parse().unwrap();
+            let active_clients = Mutex::new(HashMap::new());
+            let mut device_tasks = HashMap::new();
+            let mut g = client(addr);
+            // Queued as soon as it fires, before `Expire` is handled
+            g.timeout = Some(::async_std::task::spawn(async {}));
+            active_clients.lock().await.insert(addr, g);
+
+            drop_clients(&active_clients, &mut device_tasks, vec![addr]).await;
+            time_out_client(&server, &active_clients, &mut device_tasks, addr).await;
+            assert!(active_clients.lock().await.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_newly_allowed_device() {
+        use crate::proto::tests::touchpad;
+        let frame = Event::InputFrame((1, 1, Vec::new()));
+        let mut devices = HashMap::new();
+        devices.insert(1, touchpad());
+        let new_device = Event::NewDevice((1, Box::new(touchpad())));
+        ::async_std::task::block_on(async {
+            // Connected while the device wasn't allowed yet, then a reload allowed it
+            let mut g = client("127.0.0.1:3241".parse().unwrap());
+            let sync = Event::ClientPacket(ClientMessage::Sync(HashMap::new()));
+            assert!(g.handle_event(&sync, &HashMap::new()).await.is_some());
+            assert!(g.handle_event(&frame, &devices).await.is_none());
+            assert!(matches!(
+                g.handle_event(&new_device, &devices).await,
+                Some(ServerMessage::Sync(_))
+            ));
+            assert!(matches!(
+                g.handle_event(&frame, &devices).await,
+                Some(ServerMessage::Frame((1, 1, _)))
+            ));
+            assert!(matches!(
+                g.handle_event(&Event::KeyState, &devices).await,
+                Some(ServerMessage::KeyState(_))
+            ));
+
+            // But not to a guest that can't use it
+            let mut g = client("127.0.0.1:3242".parse().unwrap());
+            g.devices.allow.push("keyboard".to_owned());
+            assert!(g.handle_event(&new_device, &devices).await.is_none());
+            assert!(g.handle_event(&frame, &devices).await.is_none());
+        })
+    }
+}
@@ -1,5 +1,5 @@
 #![allow(dead_code, non_camel_case_types)]
-use ::nix::{ioctl_write_ptr, ioctl_write_int, ioctl_none, ioctl_read};
+use ::nix::{ioctl_write_ptr, ioctl_write_int, ioctl_none, ioctl_read, ioctl_readwrite};
 use ::libc::{c_char, c_uint};
 
 pub const UINPUT_MAX_NAME_SIZE: usize = 80;
@@ -13,25 +13,37 @@ pub struct uinput_setup {
     pub ff_effects_max: u32,
 }
 
-//#[repr(C)]
-//pub struct uinput_ff_upload {
-//	pub request_id: uint32_t,
-//	pub retval:     int32_t,
-//	pub effect:     ff_effect,
-//	pub old:        ff_effect,
-//}
-//
-//#[repr(C)]
-//pub struct uinput_ff_erase {
-//	pub request_id: uint32_t,
-//	pub retval:     int32_t,
-//	pub effect_id:  uint32_t,
-//}
+#[repr(C)]
+pub struct uinput_abs_setup {
+    pub code: u16,
+    pub absinfo: crate::evdev::raw::input_absinfo,
+}
+
+#[repr(C)]
+pub struct uinput_ff_upload {
+    pub request_id: u32,
+    pub retval:     i32,
+    pub effect:     crate::evdev::raw::ff_effect,
+    pub old:        crate::evdev::raw::ff_effect,
+}
+
+#[repr(C)]
+pub struct uinput_ff_erase {
+    pub request_id: u32,
+    pub retval:     i32,
+    pub effect_id:  u32,
+}
+
+/// Event type of the requests uinput sends to us
+pub const EV_UINPUT: u16    = 0x0101;
+pub const UI_FF_UPLOAD: u16 = 1;
+pub const UI_FF_ERASE: u16  = 2;
 
 ioctl_none!(ui_dev_create,       b'U', 1);
 ioctl_none!(ui_dev_destroy,      b'U', 2);
 
 ioctl_write_ptr!(ui_dev_setup,   b'U',   3, uinput_setup);
+ioctl_write_ptr!(ui_abs_setup,   b'U',   4, uinput_abs_setup);
 ioctl_write_int!(ui_set_evbit,   b'U', 100);
 ioctl_write_int!(ui_set_keybit,  b'U', 101);
 ioctl_write_int!(ui_set_relbit,  b'U', 102);
@@ -44,11 +56,10 @@ ioctl_write_ptr!(ui_set_phys,    b'U', 108, *const c_char);
 ioctl_write_int!(ui_set_swbit,   b'U', 109);
 ioctl_write_int!(ui_set_propbit, b'U', 110);
 
-//ioctl!(readwrite ui_begin_ff_upload with b'U', 200, uinput_ff_upload);
-//ioctl!(readwrite ui_end_ff_upload with b'U', 201, uinput_ff_upload);
-
-//ioctl!(readwrite ui_begin_ff_erase with b'U', 200, uinput_ff_erase);
-//ioctl!(readwrite ui_end_ff_erase with b'U', 201, uinput_ff_erase);
+ioctl_readwrite!(ui_begin_ff_upload, b'U', 200, uinput_ff_upload);
+ioctl_write_ptr!(ui_end_ff_upload,   b'U', 201, uinput_ff_upload);
+ioctl_readwrite!(ui_begin_ff_erase,  b'U', 202, uinput_ff_erase);
+ioctl_write_ptr!(ui_end_ff_erase,    b'U', 203, uinput_ff_erase);
 
 ioctl_read!(ui_get_version,      b'U',  45, c_uint);
 
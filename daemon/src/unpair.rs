@@ -0,0 +1,164 @@
+//! Forgetting a peer, and telling it so, so it can forget us too. The notice is sent over a
+//! cdgram session, so it's authenticated like everything else the peers tell each other.
+use crate::proto::{ClientMessage, ServerMessage};
+use ::anyhow::{anyhow, Context, Result};
+use ::async_std::net::UdpSocket;
+use ::cdgram::CDGramClient;
+use ::config::{Config, Peer};
+use ::log::{debug, info};
+use ::sodiumoxide::crypto::box_::{PublicKey, SecretKey};
+use ::std::collections::HashSet;
+use ::std::path::{Path, PathBuf};
+use ::std::time::Duration;
+
+/// The file next to the config file at `path` where `run` lists the keys of the peers it removed
+/// without telling them, for the running server daemon to tell them once it reloads the config
+fn notice_path(path: &Path) -> PathBuf {
+    ::config::state_path(path, ".unpaired")
+}
+
+/// Ask the running server daemon to tell `peer` it has been unpaired, see `notice_path`.
+fn leave_notice(path: &Path, peer: &Peer) -> Result<()> {
+    let notice = notice_path(path);
+    let mut contents = match ::std::fs::read_to_string(&notice) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ::std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", notice.display())),
+    };
+    for pk in peer.public_keys() {
+        contents.push_str(&::base64::encode(pk));
+        contents.push('\n');
+    }
+    ::config::write_private(&notice, contents.as_bytes())
+}
+
+/// Take the keys of the peers removed by `run` since the last call, for the server daemon to tell
+/// them they have been unpaired. Peers removed from the config file in any other way aren't
+/// listed.
+pub(crate) fn take_notices(path: &Path) -> HashSet<PublicKey> {
+    let notice = notice_path(path);
+    let contents = ::std::fs::read_to_string(&notice).unwrap_or_default();
+    let _ = ::std::fs::remove_file(&notice);
+    contents
+        .lines()
+        .filter_map(|line| PublicKey::from_slice(&::base64::decode(line).ok()?))
+        .collect()
+}
+
+/// Remove the peer using key `pk` from the config file at `path`. Returns the removed peer,
+/// `None` if it wasn't there.
+fn remove_peer(path: &Path, pk: &PublicKey) -> Result<Option<Peer>> {
+    let mut cfg = Config::read(path)?;
+    let i = match cfg
+        .peers
+        .iter()
+        .position(|p| p.public_keys().any(|k| k == *pk))
+    {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    if let Some(origin) = cfg.peers[i].origin() {
+        return Err(anyhow!(
+            "The peer is defined in {}, it has to be removed there",
+            origin.display()
+        ));
+    }
+    let peer = cfg.peers.remove(i);
+    cfg.save(path)?;
+    Ok(Some(peer))
+}
+
+/// Handle the peer using key `sender` telling us it has unpaired from us, by removing it from
+/// the config file at `path` too.
+pub(crate) async fn accept_unpairing(sender: PublicKey, path: Option<&Path>) -> Result<()> {
+    let path = path
+        .with_context(|| "Config isn't from a file, can't remove the peer".to_owned())?
+        .to_owned();
+    info!(
+        "Peer {} has unpaired from us",
+        ::config::fingerprint(&sender)
+    );
+    ::async_std::task::spawn_blocking(move || remove_peer(&path, &sender))
+        .await
+        .map(|_| ())
+}
+
+/// Tell the server at `addr`, with key `server_pk`, that we are unpairing from it.
+async fn notify(
+    pk: PublicKey,
+    sk: SecretKey,
+    server_pk: PublicKey,
+    addr: ::std::net::SocketAddr,
+) -> Result<()> {
+    use ::async_std::future::timeout;
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    let mut client = CDGramClient::new(pk, sk, server_pk, socket);
+    timeout(Duration::from_secs(1), client.connect(addr))
+        .await
+        .map_err(|_| anyhow!("Timed out connecting"))??;
+    let msg = ::bincode::serialize(&ClientMessage::Unpaired)?;
+    for _ in 0..3 {
+        client.send(&msg).await?;
+        if let Ok(reply) = timeout(Duration::from_secs(1), client.recv()).await {
+            if let ServerMessage::Unpaired = ::bincode::deserialize(&reply?)? {
+                return Ok(());
+            }
+        }
+    }
+    Err(anyhow!("No acknowledgement"))
+}
+
+/// Remove a peer from the config file at `path`, telling it first if it has an address we can
+/// reach it at. Clients connected to the running server daemon are told by the daemon instead.
+pub(crate) async fn run(path: &Path, opts: super::EntangledUnpairOpts) -> Result<()> {
+    let mut cfg = Config::read(path)?;
+    let i = cfg.find_peer(&opts.peer)?;
+    if let Some(origin) = cfg.peers[i].origin() {
+        return Err(anyhow!(
+            "{} is defined in {}, remove it there",
+            opts.peer,
+            origin.display()
+        ));
+    }
+
+    let addr = cfg.peers[i].addr.clone().filter(|_| !opts.local);
+    let mut told = false;
+    if let Some(addr) = addr {
+        // Only needed to talk to the peer
+        cfg.load_secret(::config::passphrase_from_env_or_stdin)?;
+        let peer = &cfg.peers[i];
+        let resolved = {
+            let addr = addr.clone();
+            ::async_std::task::spawn_blocking(move || addr.resolve()).await
+        };
+        'peer: for server_pk in peer.public_keys() {
+            for resolved in resolved.iter().flatten() {
+                match notify(cfg.public(), cfg.secret(), server_pk, *resolved).await {
+                    Ok(()) => {
+                        told = true;
+                        break 'peer;
+                    }
+                    Err(e) => debug!("Failed to tell {} at {}: {}", addr, resolved, e),
+                }
+            }
+        }
+        if told {
+            println!("Told {} about the unpairing", addr);
+        } else {
+            println!("Couldn't reach {}", addr);
+        }
+    }
+    if !told && !opts.local {
+        println!(
+            "If the peer is connected to the running server daemon, the daemon tells it instead"
+        );
+    }
+
+    let peer = cfg.peers.remove(i);
+    if !told && !opts.local {
+        leave_notice(path, &peer)?;
+    }
+    cfg.save(path)?;
+    println!("Removed {}", peer.fingerprint());
+    Ok(())
+}
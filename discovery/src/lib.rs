@@ -0,0 +1,154 @@
+//! Finding entangle servers and pairing offers on the local network.
+//!
+//! Whoever wants to be found broadcasts a beacon to `DISCOVERY_PORT` every second. Beacons are
+//! not authenticated, they only save typing in addresses: pairing still has to be verified, and
+//! the daemon only talks to servers whose keys it knows.
+use ::anyhow::{Context, Result};
+use ::serde_derive::{Deserialize, Serialize};
+use ::std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::sync::Arc;
+use ::std::time::{Duration, Instant};
+
+pub const DISCOVERY_PORT: u16 = 3242;
+const MAGIC: &[u8] = b"entangle-beacon\0";
+const INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A running server daemon
+    Server,
+    /// `pair -l` waiting for a client
+    PairingOffer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Beacon {
+    pub kind: Kind,
+    /// Friendly name of the announcing machine
+    pub name: String,
+    /// Port the daemon or `pair` is listening on
+    pub port: u16,
+    /// Public key of the announcing machine
+    pub public: Vec<u8>,
+}
+
+impl Beacon {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend(::bincode::serialize(self).unwrap());
+        data
+    }
+    fn decode(data: &[u8]) -> Option<Self> {
+        let data = data.strip_prefix(MAGIC)?;
+        ::bincode::deserialize(data).ok()
+    }
+}
+
+/// Broadcasts a beacon until dropped
+pub struct Announcer {
+    stop: Arc<AtomicBool>,
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Start broadcasting `beacon` on the local network, from a separate thread.
+pub fn announce(beacon: &Beacon) -> Result<Announcer> {
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.set_broadcast(true)?;
+    let data = beacon.encode();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = stop.clone();
+    ::std::thread::spawn(move || {
+        while !stop2.load(Ordering::Relaxed) {
+            // Errors are expected while the network is down, just keep trying
+            let _ = sock.send_to(&data, (Ipv4Addr::BROADCAST, DISCOVERY_PORT));
+            ::std::thread::sleep(INTERVAL);
+        }
+    });
+    Ok(Announcer { stop })
+}
+
+/// Bind a socket to `DISCOVERY_PORT`, with `SO_REUSEADDR` so more than one program can listen
+/// for beacons at the same time.
+fn bind_discovery_port() -> ::nix::Result<UdpSocket> {
+    use ::nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType};
+    use ::std::os::unix::io::FromRawFd;
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // Closes `fd` if anything below fails
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    let addr = socket::InetAddr::new(socket::IpAddr::new_v4(0, 0, 0, 0), DISCOVERY_PORT);
+    socket::bind(fd, &socket::SockAddr::new_inet(addr))?;
+    Ok(sock)
+}
+
+/// Listen for beacons of `kind` for `duration`. Returns every machine heard from once, with the
+/// address it's listening on.
+pub fn discover(kind: Kind, duration: Duration) -> Result<Vec<(SocketAddr, Beacon)>> {
+    let sock = bind_discovery_port()
+        .with_context(|| format!("Failed to listen for beacons on port {}", DISCOVERY_PORT))?;
+    let deadline = Instant::now() + duration;
+    let mut found: Vec<(SocketAddr, Beacon)> = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if timeout == Duration::from_secs(0) {
+            break;
+        }
+        sock.set_read_timeout(Some(timeout))?;
+        let (size, from) = match sock.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if e.kind() == ::std::io::ErrorKind::WouldBlock
+                    || e.kind() == ::std::io::ErrorKind::TimedOut =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let beacon = match Beacon::decode(&buf[..size]) {
+            Some(beacon) if beacon.kind == kind => beacon,
+            _ => continue,
+        };
+        let addr = SocketAddr::new(from.ip(), beacon.port);
+        if !found
+            .iter()
+            .any(|(a, b)| *a == addr && b.public == beacon.public)
+        {
+            found.push((addr, beacon));
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_beacon_encoding() {
+        let beacon = super::Beacon {
+            kind: super::Kind::Server,
+            name: "desktop".to_owned(),
+            port: 3241,
+            public: vec![7; 32],
+        };
+        let data = beacon.encode();
+        assert_eq!(super::Beacon::decode(&data), Some(beacon));
+        assert_eq!(super::Beacon::decode(&data[1..]), None);
+    }
+
+    #[test]
+    fn test_shared_port() {
+        let _first = super::bind_discovery_port().unwrap();
+        let _second = super::bind_discovery_port().unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+//! What each side tells the other about itself, once the session keys are agreed on. This is
+//! sent authenticated, so it can be trusted as much as the key in it.
+use ::anyhow::{anyhow, Context, Result};
+use ::config::{Config, Peer, PeerAddr};
+use ::serde_derive::{Deserialize, Serialize};
+use ::sodiumoxide::crypto::box_::PublicKey;
+
+/// Names are cut to this many characters, so the packet stays small
+const MAX_NAME_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Info {
+    /// Long term public key
+    pub public: Vec<u8>,
+    /// Friendly name, empty if there is none
+    pub name: String,
+    /// Ports the server daemon listens on, preferred first
+    pub ports: Vec<u16>,
+    /// Version of the protocol the daemon speaks
+    pub protocol: u32,
+}
+
+impl Info {
+    /// What we tell the other side about ourselves
+    pub fn ours(cfg: &Config) -> Self {
+        Self {
+            public: cfg.public().as_ref().to_vec(),
+            name: cfg
+                .name()
+                .unwrap_or_default()
+                .chars()
+                .take(MAX_NAME_CHARS)
+                .collect(),
+            ports: vec![cfg.port()],
+            protocol: ::config::PROTOCOL_VERSION,
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        ::bincode::serialize(self).unwrap()
+    }
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        ::bincode::deserialize(data).context("Malformed peer information")
+    }
+    pub fn public_key(&self) -> Result<PublicKey> {
+        PublicKey::from_slice(&self.public).ok_or_else(|| anyhow!("Malformed peer key"))
+    }
+    /// The peer entry for whoever sent this, reachable at `host`
+    pub fn into_peer(self, host: &str) -> Result<Peer> {
+        let addr = self.ports.first().map(|port| PeerAddr::new(host, *port));
+        let mut peer = Peer::new(addr, self.public_key()?);
+        if !self.name.is_empty() {
+            peer.name = Some(self.name);
+        }
+        peer.protocol = Some(self.protocol);
+        Ok(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_into_peer() {
+        let info = super::Info {
+            public: vec![7; 32],
+            name: "desktop".to_owned(),
+            ports: vec![4000, 3241],
+            protocol: 1,
+        };
+        let info = super::Info::decode(&info.encode()).unwrap();
+        let peer = info.into_peer("192.168.1.2").unwrap();
+        assert_eq!(peer.name.as_deref(), Some("desktop"));
+        assert_eq!(peer.addr.unwrap().to_string(), "192.168.1.2:4000");
+        assert_eq!(peer.protocol, Some(1));
+
+        let info = super::Info {
+            public: vec![7; 31],
+            name: String::new(),
+            ports: Vec::new(),
+            protocol: 1,
+        };
+        assert!(info.into_peer("192.168.1.2").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+//! Pairing: agreeing on session keys with the other side, verifying it, and exchanging long term
+//! keys with it. Used by the `pair` tool, and by the server daemon while pairing is allowed.
+use ::anyhow::{anyhow, Context, Result};
+use ::config::{Config, Peer, PeerAddr};
+use ::sodiumoxide::crypto::{box_, kx};
+use ::static_assertions::const_assert;
+use ::std::mem::MaybeUninit;
+use ::std::net::IpAddr;
+use ::std::time::Duration;
+
+pub mod info;
+pub mod link;
+pub mod pake;
+
+use info::Info;
+use link::Link;
+
+/// How long to wait for the other side to answer at all
+pub const CONTACT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the other side, while its user might still be comparing numbers
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How pairing failed, for whoever needs to tell the cases apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The code, the secret or the key didn't match, or the user said no
+    Rejected,
+    /// The other side didn't answer
+    TimedOut,
+    /// The other side gave up
+    Aborted,
+}
+
+impl ::std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str(match self {
+            Failure::Rejected => "Pairing rejected",
+            Failure::TimedOut => "Timed out waiting for the other side",
+            Failure::Aborted => "The other side aborted the pairing",
+        })
+    }
+}
+
+impl ::std::error::Error for Failure {}
+
+/// How to decide whether to pair with whoever is on the other side
+pub struct Verify {
+    /// One-time code or pre-agreed secret to use with CPace
+    pub code: Option<String>,
+    /// Fingerprint the other side's key must have
+    pub expect: Option<String>,
+}
+
+impl Verify {
+    /// Fail unless `pk` is the key we expect
+    fn check(&self, pk: &box_::PublicKey) -> Result<()> {
+        let expect = match &self.expect {
+            Some(expect) => expect,
+            None => return Ok(()),
+        };
+        if !has_fingerprint(pk, expect) {
+            return Err(anyhow::Error::new(Failure::Rejected).context(format!(
+                "The other side's key has fingerprint {}, expected {}",
+                ::config::fingerprint(pk),
+                expect
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `pk` has `fingerprint`, however it's capitalized or punctuated
+pub fn has_fingerprint(pk: &box_::PublicKey, fingerprint: &str) -> bool {
+    normalize_fingerprint(&::config::fingerprint(pk)) == normalize_fingerprint(fingerprint)
+}
+
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(char::is_ascii_hexdigit)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+const_assert!(kx::SESSIONKEYBYTES >= ::sodiumoxide::crypto::pwhash::argon2id13::SALTBYTES);
+
+fn generate_pin((a, b): (&kx::SessionKey, &kx::SessionKey)) -> Result<u32> {
+    use ::sodiumoxide::crypto::pwhash::argon2id13 as pwhash;
+    let salt = pwhash::Salt::from_slice(&b[0..pwhash::SALTBYTES]).unwrap();
+    let mut key: MaybeUninit<[u8; pwhash::HASHEDPASSWORDBYTES]> = MaybeUninit::uninit();
+    pwhash::derive_key(
+        unsafe { &mut *key.as_mut_ptr() },
+        a.as_ref(),
+        &salt,
+        pwhash::OPSLIMIT_INTERACTIVE,
+        pwhash::MEMLIMIT_INTERACTIVE,
+    )
+    .map_err(|()| anyhow!("Failed to calculate key"))?;
+    let key = unsafe { key.assume_init() };
+
+    use ::byteorder::{LittleEndian, ReadBytesExt};
+    let pin = key.as_ref().read_u32::<LittleEndian>()?;
+    Ok(pin % 1_0000_0000)
+}
+
+/// Append an authentication tag to `buf`
+fn seal_auth(buf: &[u8], tx: &kx::SessionKey) -> Vec<u8> {
+    use ::sodiumoxide::crypto::onetimeauth as auth;
+    let tx = auth::Key::from_slice(tx.as_ref()).unwrap();
+    let tag = auth::authenticate(buf, &tx);
+    let mut data = Vec::new();
+    data.extend(buf);
+    data.extend(tag.as_ref());
+    data
+}
+
+/// Verify the authentication tag at the end of `data`, and return the message without it
+fn open_auth<'a>(data: &'a [u8], rx: &kx::SessionKey) -> Result<&'a [u8]> {
+    use ::sodiumoxide::crypto::onetimeauth as auth;
+    if data.len() < auth::TAGBYTES {
+        return Err(anyhow!("Message is too short"));
+    }
+    let (msg, tag) = data.split_at(data.len() - auth::TAGBYTES);
+    let tag = auth::Tag::from_slice(tag).unwrap();
+    let rx = auth::Key::from_slice(rx.as_ref()).unwrap();
+    if !auth::verify(&tag, msg, &rx) {
+        Err(anyhow!("Failed to verify the message"))
+    } else {
+        Ok(msg)
+    }
+}
+
+/// Agree on temporary session keys with the client, whose handshake is `hello`. Returns the
+/// keys for receiving and for sending.
+async fn server_handshake(
+    link: &Link,
+    hello: &[u8],
+    verify: &Verify,
+    confirm: impl FnOnce(u32) -> Result<bool>,
+) -> Result<(kx::SessionKey, kx::SessionKey)> {
+    if let Some(code) = &verify.code {
+        if hello.len() != pake::SID_BYTES + pake::MESSAGE_BYTES {
+            return Err(anyhow!(
+                "Malformed handshake packet, is the client pairing with a code too?"
+            ));
+        }
+        let (sid, client_msg) = hello.split_at(pake::SID_BYTES);
+        let cpace = pake::Cpace::new(code, sid)?;
+        link.answer(link::HELLO, link::HELLO_REPLY, cpace.message())
+            .await?;
+        return cpace.finish(client_msg, false);
+    }
+
+    if hello.len() != kx::PUBLICKEYBYTES {
+        return Err(anyhow!("Malformed handshake packet"));
+    }
+    // Temporary keys for pairing
+    let (pk, sk) = kx::gen_keypair();
+    link.answer(link::HELLO, link::HELLO_REPLY, pk.as_ref())
+        .await?;
+
+    // Generate temporary session keys
+    let (rx, tx) = kx::server_session_keys(&pk, &sk, &kx::PublicKey::from_slice(hello).unwrap())
+        .map_err(|()| anyhow!("Failed to generate the shared secret"))?;
+    if verify.expect.is_none() && !confirm(generate_pin((&rx, &tx))?)? {
+        return Err(Failure::Rejected.into());
+    }
+    Ok((rx, tx))
+}
+
+/// Pair with the client at `client`, whose handshake is `hello`. Unless `verify` says how to
+/// verify the client, `confirm` is asked whether the client shows the same number.
+pub async fn server_exchange(
+    cfg: &Config,
+    link: &Link,
+    client: IpAddr,
+    hello: &[u8],
+    verify: &Verify,
+    confirm: impl FnOnce(u32) -> Result<bool>,
+) -> Result<Peer> {
+    let (rx, tx) = server_handshake(link, hello, verify, confirm).await?;
+
+    // Receive client public key
+    let client_info = link.recv(link::KEY, CONFIRM_TIMEOUT).await?;
+    let client_info = open_auth(&client_info, &rx)
+        .map_err(|_| Failure::Rejected)
+        .with_context(|| {
+            if verify.code.is_some() {
+                "The client entered the wrong code"
+            } else {
+                "Failed to receive the client key"
+            }
+        })?;
+    let client_info = Info::decode(client_info)?;
+    verify.check(&client_info.public_key()?)?;
+
+    // Send server public key
+    let info = Info::ours(cfg).encode();
+    link.answer(link::KEY, link::KEY, &seal_auth(&info, &tx))
+        .await?;
+    client_info.into_peer(&client.to_string())
+}
+
+/// Agree on temporary session keys with the server. Returns the keys for receiving and for
+/// sending.
+async fn client_handshake(
+    link: &Link,
+    verify: &Verify,
+    confirm: impl FnOnce(u32) -> Result<bool>,
+) -> Result<(kx::SessionKey, kx::SessionKey)> {
+    if let Some(code) = &verify.code {
+        let sid = ::sodiumoxide::randombytes::randombytes(pake::SID_BYTES);
+        let cpace = pake::Cpace::new(code, &sid)?;
+        let mut hello = sid.clone();
+        hello.extend(cpace.message());
+        let reply = link
+            .request(link::HELLO, &hello, link::HELLO_REPLY, CONTACT_TIMEOUT)
+            .await?;
+        return cpace.finish(&reply, true);
+    }
+
+    // Temporary keys for pairing
+    let (pk, sk) = kx::gen_keypair();
+    let server_pk = link
+        .request(link::HELLO, pk.as_ref(), link::HELLO_REPLY, CONTACT_TIMEOUT)
+        .await?;
+    let server_pk = kx::PublicKey::from_slice(&server_pk)
+        .with_context(|| "Malformed handshake packet".to_owned())?;
+
+    // Generate temporary session keys
+    let (rx, tx) = kx::client_session_keys(&pk, &sk, &server_pk)
+        .map_err(|()| anyhow!("Failed to generate the shared secret"))?;
+    if verify.expect.is_none() && !confirm(generate_pin((&tx, &rx))?)? {
+        return Err(Failure::Rejected.into());
+    }
+    Ok((rx, tx))
+}
+
+/// Pair with the server at `server`. Unless `verify` says how to verify the server, `confirm`
+/// is asked whether the server shows the same number.
+pub async fn client_exchange(
+    cfg: &Config,
+    link: &Link,
+    server: &PeerAddr,
+    verify: &Verify,
+    confirm: impl FnOnce(u32) -> Result<bool>,
+) -> Result<Peer> {
+    let (rx, tx) = client_handshake(link, verify, confirm).await?;
+
+    // Send client public key, and receive server public key
+    let info = Info::ours(cfg).encode();
+    let server_info = link
+        .request(
+            link::KEY,
+            &seal_auth(&info, &tx),
+            link::KEY,
+            CONFIRM_TIMEOUT,
+        )
+        .await
+        .with_context(|| {
+            if verify.code.is_some() {
+                "Pairing failed, was the code right?"
+            } else {
+                "Pairing failed"
+            }
+        })?;
+    let server_info = open_auth(&server_info, &rx)
+        .map_err(|_| Failure::Rejected)
+        .context("Failed to receive the server key")?;
+    let server_info = Info::decode(server_info)?;
+    verify.check(&server_info.public_key()?)?;
+    // Keep the host as given, it might be a name that resolves to a different ip later
+    server_info.into_peer(server.host())
+}
@@ -0,0 +1,211 @@
+//! Just enough reliability for pairing over UDP.
+//!
+//! Every packet starts with `MAGIC`, so pairing can share a port with other protocols, followed
+//! by a type byte. The side that needs an answer retransmits its packet until the answer arrives,
+//! and the other side answers retransmissions by resending the answer it already sent. Either
+//! side can end the pairing with `ABORT`. `ABORT` can't be authenticated (the keys might not even
+//! match), so anyone on the path can abort a pairing, but they could just as well drop the
+//! packets.
+use crate::Failure;
+use ::anyhow::Result;
+use ::async_std::channel::{Receiver, Sender};
+use ::async_std::net::{SocketAddr, UdpSocket};
+use ::async_std::sync::{Arc, Mutex};
+use ::async_std::task::JoinHandle;
+use ::std::collections::{HashMap, HashSet};
+use ::std::time::{Duration, Instant};
+
+const MAGIC: &[u8] = b"entangle-pair\0";
+
+/// The client's handshake
+pub const HELLO: u8 = 1;
+/// The server's answer to `HELLO`
+pub const HELLO_REPLY: u8 = 2;
+/// A long term public key, authenticated with the session keys
+pub const KEY: u8 = 3;
+/// The sender has given up on pairing
+pub const ABORT: u8 = 4;
+
+const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long we keep answering retransmissions once we are done
+const LINGER: Duration = Duration::from_secs(3);
+
+pub struct Link {
+    sock: Arc<UdpSocket>,
+    peer: SocketAddr,
+    packets: Receiver<(u8, Vec<u8>)>,
+    /// Packets to resend when a packet of a given type is received again
+    answers: Arc<Mutex<HashMap<u8, Vec<u8>>>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Split a pairing packet into its type and payload. Returns `None` if `packet` isn't a pairing
+/// packet at all.
+pub fn parse(packet: &[u8]) -> Option<(u8, &[u8])> {
+    let (&ty, payload) = packet.strip_prefix(MAGIC)?.split_first()?;
+    Some((ty, payload))
+}
+
+fn packet(ty: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = MAGIC.to_vec();
+    packet.push(ty);
+    packet.extend(payload);
+    packet
+}
+
+async fn receive(
+    sock: Arc<UdpSocket>,
+    peer: SocketAddr,
+    incoming: Receiver<Vec<u8>>,
+    mut seen: HashSet<u8>,
+    answers: Arc<Mutex<HashMap<u8, Vec<u8>>>>,
+    tx: Sender<(u8, Vec<u8>)>,
+) {
+    while let Ok(packet) = incoming.recv().await {
+        let (ty, payload) = match parse(&packet) {
+            Some(packet) => packet,
+            None => continue,
+        };
+        if let Some(answer) = answers.lock().await.get(&ty) {
+            let _ = sock.send_to(answer, peer).await;
+        } else if seen.insert(ty) && tx.send((ty, payload.to_vec())).await.is_err() {
+            break;
+        }
+        // Otherwise it's a retransmission we haven't answered yet
+    }
+}
+
+impl Link {
+    /// A link to `peer` through `sock`, which might be used for other things as well. Packets
+    /// from `peer` have to be passed in through `incoming`. `seen` are the types of the packets
+    /// already received from it.
+    pub fn new(
+        sock: Arc<UdpSocket>,
+        peer: SocketAddr,
+        incoming: Receiver<Vec<u8>>,
+        seen: &[u8],
+    ) -> Self {
+        let answers = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, packets) = ::async_std::channel::unbounded();
+        let task = ::async_std::task::spawn(receive(
+            sock.clone(),
+            peer,
+            incoming,
+            seen.iter().copied().collect(),
+            answers.clone(),
+            tx,
+        ));
+        Self {
+            sock,
+            peer,
+            packets,
+            answers,
+            tasks: vec![task],
+        }
+    }
+
+    /// A link through `sock`, which has to be connected to the other side already, and is used
+    /// for nothing else.
+    pub fn connected(sock: UdpSocket, seen: &[u8]) -> Result<Self> {
+        let peer = sock.peer_addr()?;
+        let sock = Arc::new(sock);
+        let (tx, incoming) = ::async_std::channel::unbounded();
+        let sock2 = sock.clone();
+        let forward = ::async_std::task::spawn(async move {
+            let mut buf = [0; 1024];
+            loop {
+                let size = match sock2.recv(&mut buf).await {
+                    Ok(size) => size,
+                    // e.g. ECONNREFUSED because the other side isn't listening yet, or anymore
+                    Err(_) => continue,
+                };
+                if tx.send(buf[..size].to_vec()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let mut link = Self::new(sock, peer, incoming, seen);
+        link.tasks.push(forward);
+        Ok(link)
+    }
+
+    /// Send `payload` as a packet of type `ty`, in answer to the packet of type `answering`.
+    /// Retransmissions of that packet are answered with the same packet.
+    pub async fn answer(&self, answering: u8, ty: u8, payload: &[u8]) -> Result<()> {
+        let packet = packet(ty, payload);
+        self.sock.send_to(&packet, self.peer).await?;
+        self.answers.lock().await.insert(answering, packet);
+        Ok(())
+    }
+
+    /// Wait for a packet of type `ty` until `deadline`. Returns `None` if it didn't arrive in
+    /// time.
+    async fn recv_until(&self, ty: u8, deadline: Instant) -> Result<Option<Vec<u8>>> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let (got, payload) =
+                match ::async_std::future::timeout(timeout, self.packets.recv()).await {
+                    Ok(packet) => packet?,
+                    Err(_) => return Ok(None),
+                };
+            if got == ABORT {
+                return Err(Failure::Aborted.into());
+            }
+            if got == ty {
+                return Ok(Some(payload));
+            }
+        }
+    }
+
+    /// Wait for a packet of type `ty`, for at most `timeout`
+    pub async fn recv(&self, ty: u8, timeout: Duration) -> Result<Vec<u8>> {
+        self.recv_until(ty, Instant::now() + timeout)
+            .await?
+            .ok_or_else(|| Failure::TimedOut.into())
+    }
+
+    /// Send `payload` as a packet of type `ty`, and retransmit it until a packet of type `reply`
+    /// arrives, for at most `timeout`.
+    pub async fn request(
+        &self,
+        ty: u8,
+        payload: &[u8],
+        reply: u8,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let packet = packet(ty, payload);
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            self.sock.send_to(&packet, self.peer).await?;
+            let next = (Instant::now() + RETRANSMIT_INTERVAL).min(deadline);
+            if let Some(payload) = self.recv_until(reply, next).await? {
+                return Ok(payload);
+            }
+        }
+        Err(Failure::TimedOut.into())
+    }
+
+    async fn close(self) {
+        for task in self.tasks {
+            task.cancel().await;
+        }
+    }
+
+    /// Tell the other side we are giving up
+    pub async fn abort(self) {
+        // Nobody is going to retransmit this for us, so send a few
+        let packet = packet(ABORT, &[]);
+        for _ in 0..3 {
+            let _ = self.sock.send_to(&packet, self.peer).await;
+        }
+        self.close().await;
+    }
+
+    /// Keep answering retransmissions for a while, in case our last answer got lost. Fails if the
+    /// other side aborts meanwhile, e.g. because it didn't like our key.
+    pub async fn finish(self) -> Result<()> {
+        let result = self.recv_until(ABORT, Instant::now() + LINGER).await;
+        self.close().await;
+        result.map(|_| ())
+    }
+}
@@ -1,50 +1,89 @@
 #[allow(unused_imports)]
 use ::anyhow::{anyhow, Context, Result};
 use ::argh::FromArgs;
-use ::config::Config;
-use ::static_assertions::const_assert;
-use ::std::mem::MaybeUninit;
-use ::std::net::SocketAddr;
+use ::config::{Config, Peer, PeerAddr};
+use ::pair::link::{self, Link};
+use ::pair::{pake, Failure, Verify};
+use ::sodiumoxide::crypto::box_;
+use ::std::time::Duration;
 
 #[derive(FromArgs)]
 /// Pair host with client
+#[argh(
+    error_code(1, "Something went wrong, e.g. the config file couldn't be written"),
+    error_code(
+        2,
+        "Pairing was rejected: wrong code or secret, unexpected key, or the user said no"
+    ),
+    error_code(3, "The other side didn't answer in time"),
+    error_code(4, "The other side aborted the pairing")
+)]
 struct Pair {
+    /// path to your configuration file (default: /etc/entangle.conf for root,
+    /// ~/.config/entangle/entangle.conf for everyone else)
+    #[argh(option, short = 'c', default = "::config::default_path()")]
+    config: ::std::path::PathBuf,
+
     /// listen for connection from a client
     #[argh(switch, short = 'l')]
     listen: bool,
 
-    /// pair with a remote host
+    /// pair with a remote host, given as <host name or ip>:<port>
     #[argh(option, short = 's')]
-    server: Option<SocketAddr>,
-}
+    server: Option<PeerAddr>,
 
-const_assert!(
-    ::sodiumoxide::crypto::kx::SESSIONKEYBYTES
-        >= ::sodiumoxide::crypto::pwhash::argon2id13::SALTBYTES
-);
+    /// with -l, announce the pairing offer on the local network, so the client can find it with
+    /// --discover
+    #[argh(switch)]
+    announce: bool,
 
-fn generate_pin(
-    (a, b): (
-        &::sodiumoxide::crypto::kx::SessionKey,
-        &::sodiumoxide::crypto::kx::SessionKey,
-    ),
-) -> Result<u32> {
-    use ::sodiumoxide::crypto::pwhash::argon2id13 as pwhash;
-    let salt = pwhash::Salt::from_slice(&b[0..pwhash::SALTBYTES]).unwrap();
-    let mut key: MaybeUninit<[u8; pwhash::HASHEDPASSWORDBYTES]> = MaybeUninit::uninit();
-    pwhash::derive_key(
-        unsafe { &mut *key.as_mut_ptr() },
-        a.as_ref(),
-        &salt,
-        pwhash::OPSLIMIT_INTERACTIVE,
-        pwhash::MEMLIMIT_INTERACTIVE,
-    )
-    .map_err(|()| anyhow!("Failed to calculate key"))?;
-    let key = unsafe { key.assume_init() };
-
-    use ::byteorder::{LittleEndian, ReadBytesExt};
-    let pin = key.as_ref().read_u32::<LittleEndian>()?;
-    Ok(pin)
+    /// find a pairing offer on the local network, instead of giving the server with -s
+    #[argh(switch, short = 'd')]
+    discover: bool,
+
+    /// pair by typing a one-time code shown by the listening side into the other side, instead
+    /// of comparing numbers. Both sides have to use this.
+    #[argh(switch)]
+    code: bool,
+
+    /// pair without asking anything, using this pre-agreed secret instead of a one-time code.
+    /// Both sides have to use the same secret. Can also be given in ENTANGLE_PAIRING_SECRET.
+    #[argh(option)]
+    secret: Option<String>,
+
+    /// pair without asking anything, if and only if the other side's key has this fingerprint
+    #[argh(option)]
+    expect: Option<String>,
+
+    /// with -l, give up if no client makes contact within this many seconds
+    #[argh(option)]
+    wait: Option<u64>,
+
+    /// print the new peer entry as JSON
+    #[argh(switch)]
+    json: bool,
+
+    /// instead of pairing, print the fingerprint of our key, for --expect on the other side.
+    /// Generates a key first if there is none yet.
+    #[argh(switch)]
+    fingerprint: bool,
+
+    /// instead of pairing, move the secret key out of the config file into this key file
+    #[argh(option)]
+    secret_file: Option<::std::path::PathBuf>,
+
+    /// encrypt the key file given by --secret-file with a passphrase
+    #[argh(switch)]
+    encrypt_secret: bool,
+}
+
+/// Each way pairing can fail has its own exit status, so scripts can tell them apart
+fn exit_code(failure: Failure) -> i32 {
+    match failure {
+        Failure::Rejected => 2,
+        Failure::TimedOut => 3,
+        Failure::Aborted => 4,
+    }
 }
 
 fn ask(prompt: &str) -> Result<bool> {
@@ -67,153 +106,268 @@ fn ask(prompt: &str) -> Result<bool> {
         false
     })
 }
-use ::sodiumoxide::crypto::{box_, kx};
-
-/// Send an authenticated message
-async fn send_auth(
-    sock: &::async_std::net::UdpSocket,
-    buf: &[u8],
-    tx: &kx::SessionKey,
-) -> Result<()> {
-    use ::sodiumoxide::crypto::onetimeauth as auth;
-    let tx = auth::Key::from_slice(tx.as_ref()).unwrap();
-    let tag = auth::authenticate(buf, &tx);
-    let mut data = Vec::new();
-    data.extend(buf);
-    data.extend(tag.as_ref());
-
-    sock.send(data.as_slice()).await?;
-    Ok(())
+
+fn read_line(prompt: &str) -> Result<String> {
+    use ::std::io::Write;
+    print!("{}", prompt);
+    ::std::io::stdout().flush()?;
+    let mut line = String::new();
+    ::std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_owned())
 }
 
-/// Receive an authenticated message
-async fn recv_auth(
-    sock: &::async_std::net::UdpSocket,
-    buf: &mut [u8],
-    rx: &kx::SessionKey,
-) -> Result<usize> {
-    use ::sodiumoxide::crypto::onetimeauth as auth;
-    assert!(buf.len() > auth::TAGBYTES);
-    let size = sock.recv(buf).await?;
-    assert!(size <= buf.len());
-    let tag = auth::Tag::from_slice(&buf[(size - auth::TAGBYTES)..size]).unwrap();
-
-    let rx = auth::Key::from_slice(rx.as_ref()).unwrap();
-    if !auth::verify(&tag, &buf[0..(size - auth::TAGBYTES)], &rx) {
-        Err(anyhow!("Failed to verify the client message"))
-    } else {
-        Ok(size - auth::TAGBYTES)
+/// Find a pairing offer on the local network. If there is more than one, `expect` picks one by
+/// its key, otherwise the user picks one if `interactive`.
+fn discover_server(expect: Option<&str>, interactive: bool) -> Result<PeerAddr> {
+    eprintln!("Looking for pairing offers...");
+    let mut offers =
+        ::discovery::discover(::discovery::Kind::PairingOffer, Duration::from_secs(3))?;
+    if let Some(expect) = expect {
+        offers.retain(|(_, beacon)| {
+            box_::PublicKey::from_slice(&beacon.public)
+                .is_some_and(|pk| ::pair::has_fingerprint(&pk, expect))
+        });
     }
+    let (addr, _) = match &offers[..] {
+        [] => {
+            return Err(anyhow!(
+                "No pairing offers found, is `pair -l --announce` running?"
+            ))
+        }
+        [offer] => offer,
+        _ if !interactive => return Err(anyhow!("Found more than one pairing offer")),
+        _ => {
+            for (i, (addr, beacon)) in offers.iter().enumerate() {
+                println!("{}: {} at {}", i + 1, beacon.name, addr);
+            }
+            let choice: usize = read_line("Pair with: ")?.parse().context("Not a number")?;
+            offers
+                .get(choice.wrapping_sub(1))
+                .with_context(|| "No such pairing offer".to_owned())?
+        }
+    };
+    eprintln!("Pairing with {}", addr);
+    Ok((*addr).into())
 }
 
-async fn accept_client(mut cfg: Config) -> Result<Config> {
-    let sock = ::async_std::net::UdpSocket::bind("0.0.0.0:0").await?;
-    // Temporary keys for pairing
-    let (pk, sk) = kx::gen_keypair();
-    let addr = sock.local_addr()?;
-    println!("Waiting for client contact at {}", addr);
-
-    let mut buf: MaybeUninit<[u8; kx::PUBLICKEYBYTES]> = MaybeUninit::uninit();
-    let (size, remote_addr) = sock.recv_from(unsafe { &mut *buf.as_mut_ptr() }).await?;
-    if size != ::std::mem::size_of_val(&buf) {
-        return Err(anyhow!("Malformed handshake packet"));
+/// Read a passphrase from the terminal, or from `ENTANGLE_PASSPHRASE` if it is set
+fn read_passphrase(prompt: &str) -> Result<String> {
+    use ::std::io::Write;
+    use ::termion::input::TermRead;
+    if let Ok(passphrase) = ::std::env::var("ENTANGLE_PASSPHRASE") {
+        return Ok(passphrase);
     }
+    let mut stdout = ::std::io::stdout();
+    write!(stdout, "{}", prompt)?;
+    stdout.flush()?;
+    let passphrase = ::std::io::stdin()
+        .read_passwd(&mut stdout)?
+        .with_context(|| "No passphrase given".to_owned())?;
+    writeln!(stdout)?;
+    Ok(passphrase)
+}
 
-    let client_pk = unsafe { buf.assume_init() };
+/// Wait for a client to make contact, for at most `wait` if given
+async fn accept_client(
+    cfg: &Config,
+    verify: &Verify,
+    announce: bool,
+    wait: Option<Duration>,
+) -> Result<Peer> {
+    let sock = ::async_std::net::UdpSocket::bind("0.0.0.0:0").await?;
+    let addr = sock.local_addr()?;
+    eprintln!("Waiting for client contact at {}", addr);
+    let _announcer = if announce {
+        Some(::discovery::announce(&::discovery::Beacon {
+            kind: ::discovery::Kind::PairingOffer,
+            name: cfg.name().unwrap_or_default(),
+            port: addr.port(),
+            public: cfg.public().as_ref().to_vec(),
+        })?)
+    } else {
+        None
+    };
 
+    let contact = async {
+        let mut buf = [0; 1024];
+        loop {
+            let (size, remote_addr) = sock.recv_from(&mut buf).await?;
+            if let Some((link::HELLO, hello)) = link::parse(&buf[..size]) {
+                return Ok::<_, ::std::io::Error>((hello.to_vec(), remote_addr));
+            }
+        }
+    };
+    let (hello, remote_addr) = match wait {
+        Some(wait) => ::async_std::future::timeout(wait, contact)
+            .await
+            .map_err(|_| Failure::TimedOut)
+            .with_context(|| "No client made contact".to_owned())??,
+        None => contact.await?,
+    };
     sock.connect(remote_addr).await?;
-    sock.send(pk.as_ref()).await?;
-
-    // Generate temporary session keys
-    let (rx, tx) = ::sodiumoxide::crypto::kx::server_session_keys(
-        &pk,
-        &sk,
-        &kx::PublicKey::from_slice(&client_pk[..]).unwrap(),
-    )
-    .map_err(|()| anyhow!("Failed to generate the shared secret"))?;
-    let pin = generate_pin((&rx, &tx))?;
-    println!(
-        "Please verify the client displays the same number as below\n\t{}",
-        pin % 1_0000_0000
-    );
-    ask("Pair?(y/n)")?;
-
-    // Receive client public key
-    let mut buf = MaybeUninit::<[u8; 128]>::uninit();
-    let client_pk_len = recv_auth(&sock, unsafe { &mut *buf.as_mut_ptr() }, &rx).await?;
-    let client_pk = unsafe { &buf.assume_init()[0..client_pk_len] };
-    let client_pk = box_::PublicKey::from_slice(client_pk).unwrap();
-    cfg.peers.push(::config::Peer::new(None, client_pk));
-
-    // Send server public key
-    send_auth(&sock, cfg.public().as_ref(), &tx).await?;
-    Ok(cfg)
+    let link = Link::connected(sock, &[link::HELLO])?;
+    let confirm = |pin| {
+        println!(
+            "Please verify the client displays the same number as below\n\t{}",
+            pin
+        );
+        ask("Pair?(y/n)")
+    };
+    match ::pair::server_exchange(cfg, &link, remote_addr.ip(), &hello, verify, confirm).await {
+        Ok(peer) => {
+            link.finish().await?;
+            Ok(peer)
+        }
+        Err(e) => {
+            link.abort().await;
+            Err(e)
+        }
+    }
 }
 
-async fn pair_server(mut cfg: Config, mut server: SocketAddr) -> Result<Config> {
+async fn pair_server(cfg: &Config, server: PeerAddr, verify: &Verify) -> Result<Peer> {
     let sock = ::async_std::net::UdpSocket::bind("0.0.0.0:0").await?;
-    // Temporary keys for pairing
-    let (pk, sk) = kx::gen_keypair();
-    sock.connect(server).await?;
-    sock.send(pk.as_ref()).await?;
-
-    let mut buf: MaybeUninit<[u8; kx::PUBLICKEYBYTES]> = MaybeUninit::uninit();
-    let size = sock.recv(unsafe { &mut *buf.as_mut_ptr() }).await?;
-    if size != ::std::mem::size_of_val(&buf) {
-        return Err(anyhow!("Malformed handshake packet"));
+    sock.connect((server.host(), server.port()))
+        .await
+        .with_context(|| format!("Failed to connect to {}", server))?;
+    let link = Link::connected(sock, &[])?;
+    let confirm = |pin| {
+        println!(
+            "Please verify the server displays the same number as below\n\t{}",
+            pin
+        );
+        ask("Pair?(y/n)")
+    };
+    match ::pair::client_exchange(cfg, &link, &server, verify, confirm).await {
+        Ok(peer) => Ok(peer),
+        Err(e) => {
+            link.abort().await;
+            Err(e)
+        }
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {:?}", e);
+        let code = e.downcast_ref::<Failure>().map_or(1, |f| exit_code(*f));
+        ::std::process::exit(code);
     }
-    let server_pk = unsafe { buf.assume_init() };
-
-    // Generate temporary session keys
-    let (rx, tx) = ::sodiumoxide::crypto::kx::client_session_keys(
-        &pk,
-        &sk,
-        &kx::PublicKey::from_slice(&server_pk[..]).unwrap(),
-    )
-    .map_err(|()| anyhow!("Failed to generate the shared secret"))?;
-    let pin = generate_pin((&tx, &rx))?;
-    println!(
-        "Please verify the server displays the same number as below\n\t{}",
-        pin % 1_0000_0000
-    );
-    ask("Pair?(y/n)")?;
-
-    // Send client public key
-    send_auth(&sock, cfg.public().as_ref(), &tx).await?;
-
-    // Receive server public key
-    let mut buf = MaybeUninit::<[u8; 128]>::uninit();
-    let server_pk_len = recv_auth(&sock, unsafe { &mut *buf.as_mut_ptr() }, &rx).await?;
-    let server_pk = unsafe { &buf.assume_init()[0..server_pk_len] };
-    let server_pk = box_::PublicKey::from_slice(server_pk).unwrap();
-
-    server.set_port(3241);
-    cfg.peers.push(::config::Peer::new(Some(server), server_pk));
-
-    Ok(cfg)
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let opt: Pair = ::argh::from_env();
-    let config = if ::std::path::Path::new("/etc/entangle.conf").exists() {
-        let cfg = ::std::fs::read_to_string("/etc/entangle.conf")?;
-        ::toml::de::from_str(&cfg)?
+    let config_path = opt.config;
+    let mut config = if config_path.exists() {
+        Config::load(&config_path, || {
+            read_passphrase("Passphrase for the secret key: ")
+        })?
     } else {
+        if let Some(dir) = config_path.parent() {
+            ::std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
         ::config::Config::generate()
     };
 
-    let cfg = if opt.listen {
-        ::async_std::task::block_on(accept_client(config))
+    if let Some(secret_file) = opt.secret_file {
+        let passphrase = if opt.encrypt_secret {
+            let passphrase = read_passphrase("New passphrase: ")?;
+            if passphrase != read_passphrase("Repeat the passphrase: ")? {
+                return Err(anyhow!("Passphrases don't match"));
+            }
+            Some(passphrase)
+        } else {
+            None
+        };
+        config.move_secret(&secret_file, passphrase.as_deref())?;
+        config.save(&config_path)?;
+        println!("Secret key moved to {}", secret_file.display());
+        return Ok(());
+    }
+
+    if opt.fingerprint {
+        if !config_path.exists() {
+            config.save(&config_path)?;
+        }
+        println!("{}", ::config::fingerprint(&config.public()));
+        return Ok(());
+    }
+
+    let secret = opt
+        .secret
+        .or_else(|| ::std::env::var("ENTANGLE_PAIRING_SECRET").ok());
+    if let Some(expect) = &opt.expect {
+        if ::pair::normalize_fingerprint(expect).len() != 16 {
+            return Err(anyhow!(
+                "Malformed fingerprint {}, expected something like 0123:4567:89ab:cdef",
+                expect
+            ));
+        }
+    }
+    let interactive = secret.is_none() && opt.expect.is_none();
+
+    let peer = if opt.listen {
+        let code = match secret {
+            Some(secret) => Some(secret),
+            None if opt.code => {
+                let code = pake::generate_code();
+                println!("Enter this code on the client:\n\t{}", code);
+                Some(code)
+            }
+            None => None,
+        };
+        let verify = Verify {
+            code,
+            expect: opt.expect,
+        };
+        let wait = opt.wait.map(Duration::from_secs);
+        ::async_std::task::block_on(accept_client(&config, &verify, opt.announce, wait))
     } else {
-        ::async_std::task::block_on(pair_server(config, opt.server.unwrap()))
+        let server = match opt.server {
+            Some(server) => server,
+            None if opt.discover => discover_server(opt.expect.as_deref(), interactive)?,
+            None => return Err(anyhow!("Either -l, -s or -d has to be given")),
+        };
+        let code = match secret {
+            Some(secret) => Some(secret),
+            None if opt.code => Some(read_line("Pairing code shown on the server: ")?),
+            None => None,
+        };
+        let verify = Verify {
+            code,
+            expect: opt.expect,
+        };
+        ::async_std::task::block_on(pair_server(&config, server, &verify))
     }?;
 
-    use ::std::io::Write;
-    let mut cfgf = ::std::fs::File::create("/etc/entangle.conf")?;
-    write!(cfgf, "{}", toml::ser::to_string(&cfg)?)?;
-
-    use ::std::os::unix::fs::PermissionsExt;
-    let permissions = ::std::fs::Permissions::from_mode(0o600);
-    cfgf.set_permissions(permissions)?;
+    if peer.protocol != Some(::config::PROTOCOL_VERSION) {
+        eprintln!(
+            "Warning: the other side speaks protocol version {}, but we speak {}. Update the \
+             older one before using them together.",
+            peer.protocol.unwrap_or_default(),
+            ::config::PROTOCOL_VERSION
+        );
+    }
+    let json = peer_json(&peer)?;
+    config.peers.push(peer);
+    config.save(&config_path)?;
+    if opt.json {
+        println!("{}", json);
+    }
     Ok(())
 }
+
+/// The new peer entry, as it's written to the config file, plus its fingerprint
+fn peer_json(peer: &Peer) -> Result<String> {
+    #[derive(::serde_derive::Serialize)]
+    struct Entry<'a> {
+        #[serde(flatten)]
+        peer: &'a Peer,
+        fingerprint: String,
+    }
+    Ok(::serde_json::to_string(&Entry {
+        peer,
+        fingerprint: peer.fingerprint(),
+    })?)
+}
@@ -0,0 +1,143 @@
+//! CPace over ristretto255 (draft-irtf-cfrg-cpace), a balanced PAKE. Both sides derive the
+//! generator from the pairing code, so they only end up with the same session keys if they used
+//! the same code. Someone without the code gets exactly one guess per pairing attempt.
+use ::anyhow::{anyhow, Result};
+use ::libsodium_sys as ffi;
+use ::sodiumoxide::crypto::hash::sha512;
+use ::sodiumoxide::crypto::kx::SessionKey;
+
+/// Length of the session id the initiator picks
+pub const SID_BYTES: usize = 16;
+/// Length of the message each side sends
+pub const MESSAGE_BYTES: usize = 32;
+const SCALAR_BYTES: usize = 32;
+
+const DSI: &[u8] = b"CPaceRistretto255";
+const DSI_ISK: &[u8] = b"CPaceRistretto255_ISK";
+
+/// Append `data` to `buf`, prefixed with its length
+fn push_prefixed(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend(&(data.len() as u64).to_le_bytes());
+    buf.extend(data);
+}
+
+/// Remove the characters people might type between digits
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect()
+}
+
+/// A new random pairing code to show to the user
+pub fn generate_code() -> String {
+    format!(
+        "{:06}",
+        ::sodiumoxide::randombytes::randombytes_uniform(1_000_000)
+    )
+}
+
+/// One side of a CPace exchange
+pub struct Cpace {
+    sid: Vec<u8>,
+    scalar: [u8; SCALAR_BYTES],
+    message: [u8; MESSAGE_BYTES],
+}
+
+impl Cpace {
+    pub fn new(code: &str, sid: &[u8]) -> Result<Self> {
+        ::sodiumoxide::init().map_err(|()| anyhow!("Failed to initialize libsodium"))?;
+        let mut input = Vec::new();
+        push_prefixed(&mut input, DSI);
+        push_prefixed(&mut input, normalize_code(code).as_bytes());
+        push_prefixed(&mut input, sid);
+        let hash = sha512::hash(&input);
+
+        let mut generator = [0; MESSAGE_BYTES];
+        let mut scalar = [0; SCALAR_BYTES];
+        let mut message = [0; MESSAGE_BYTES];
+        unsafe {
+            ffi::crypto_core_ristretto255_from_hash(generator.as_mut_ptr(), hash.as_ref().as_ptr());
+            ffi::crypto_core_ristretto255_scalar_random(scalar.as_mut_ptr());
+            if ffi::crypto_scalarmult_ristretto255(
+                message.as_mut_ptr(),
+                scalar.as_ptr(),
+                generator.as_ptr(),
+            ) != 0
+            {
+                return Err(anyhow!("Failed to generate the pairing message"));
+            }
+        }
+        Ok(Self {
+            sid: sid.to_owned(),
+            scalar,
+            message,
+        })
+    }
+    /// The message to send to the other side
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+    /// Derive the session keys from the other side's message. Returns the keys for receiving and
+    /// for sending, like `kx` does. The keys only match the other side's if both used the same
+    /// code, which shows once the first authenticated message is received.
+    pub fn finish(self, theirs: &[u8], initiator: bool) -> Result<(SessionKey, SessionKey)> {
+        if theirs.len() != MESSAGE_BYTES {
+            return Err(anyhow!("Malformed pairing message"));
+        }
+        let mut shared = [0; MESSAGE_BYTES];
+        if unsafe {
+            ffi::crypto_scalarmult_ristretto255(
+                shared.as_mut_ptr(),
+                self.scalar.as_ptr(),
+                theirs.as_ptr(),
+            )
+        } != 0
+        {
+            return Err(anyhow!("Invalid pairing message"));
+        }
+        let (initiator_msg, responder_msg) = if initiator {
+            (&self.message[..], theirs)
+        } else {
+            (theirs, &self.message[..])
+        };
+        let mut transcript = Vec::new();
+        push_prefixed(&mut transcript, DSI_ISK);
+        push_prefixed(&mut transcript, &self.sid);
+        push_prefixed(&mut transcript, &shared);
+        push_prefixed(&mut transcript, initiator_msg);
+        push_prefixed(&mut transcript, responder_msg);
+        let isk = sha512::hash(&transcript);
+        let (to_responder, to_initiator) = isk.as_ref().split_at(isk.as_ref().len() / 2);
+        let to_responder = SessionKey::from_slice(to_responder).unwrap();
+        let to_initiator = SessionKey::from_slice(to_initiator).unwrap();
+        Ok(if initiator {
+            (to_initiator, to_responder)
+        } else {
+            (to_responder, to_initiator)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cpace;
+
+    #[test]
+    fn test_code_must_match() {
+        let sid = [1; super::SID_BYTES];
+        let a = Cpace::new("123456", &sid).unwrap();
+        let b = Cpace::new("123-456", &sid).unwrap();
+        let (a_msg, b_msg) = (a.message().to_owned(), b.message().to_owned());
+        let (a_rx, a_tx) = a.finish(&b_msg, true).unwrap();
+        let (b_rx, b_tx) = b.finish(&a_msg, false).unwrap();
+        assert_eq!(a_tx, b_rx);
+        assert_eq!(a_rx, b_tx);
+
+        let a = Cpace::new("123456", &sid).unwrap();
+        let b = Cpace::new("123457", &sid).unwrap();
+        let (a_msg, b_msg) = (a.message().to_owned(), b.message().to_owned());
+        let (_, a_tx) = a.finish(&b_msg, true).unwrap();
+        let (b_rx, _) = b.finish(&a_msg, false).unwrap();
+        assert_ne!(a_tx, b_rx);
+    }
+}
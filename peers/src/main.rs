@@ -0,0 +1,244 @@
+use ::anyhow::{anyhow, Context, Result};
+use ::argh::FromArgs;
+use ::config::{Config, Identity, Peer, PeerAddr};
+use ::std::path::{Path, PathBuf};
+
+#[derive(FromArgs)]
+/// Manage paired peers
+struct PeersOpts {
+    #[argh(
+        option,
+        short = 'c',
+        default = "::config::default_path()"
+    )]
+    /// path to your configuration file (default: /etc/entangle.conf for root,
+    /// ~/.config/entangle/entangle.conf for everyone else)
+    config: PathBuf,
+    #[argh(subcommand)]
+    subcommand: PeersSubcommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum PeersSubcommands {
+    List(ListOpts),
+    Rename(RenameOpts),
+    Remove(RemoveOpts),
+    SetAddr(SetAddrOpts),
+    Export(ExportOpts),
+    Import(ImportOpts),
+    Guest(GuestOpts),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list")]
+/// List the paired peers
+struct ListOpts {}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "rename")]
+/// Give a peer a new name
+struct RenameOpts {
+    #[argh(positional)]
+    /// name, host name or key fingerprint of the peer
+    peer: String,
+    #[argh(positional)]
+    /// the new name
+    name: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "remove")]
+/// Forget a peer
+struct RemoveOpts {
+    #[argh(positional)]
+    /// name, host name or key fingerprint of the peer
+    peer: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-addr")]
+/// Change the address of a peer
+struct SetAddrOpts {
+    #[argh(positional)]
+    /// name, host name or key fingerprint of the peer
+    peer: String,
+    #[argh(positional)]
+    /// the new address, as <host name or ip>:<port>, or "none" to remove the address
+    addr: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Print your own identity, for importing on another machine
+struct ExportOpts {
+    #[argh(option)]
+    /// address other machines can reach you at, as <host name or ip>:<port>. Only needed if you
+    /// are going to run the server.
+    addr: Option<PeerAddr>,
+    #[argh(option)]
+    /// name to give yourself (default: the host name)
+    name: Option<String>,
+    #[argh(option, short = 'o')]
+    /// write the identity to this file instead of printing it
+    output: Option<PathBuf>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Add a peer from its exported identity
+struct ImportOpts {
+    #[argh(positional)]
+    /// the identity, or a file containing it
+    identity: String,
+    #[argh(option)]
+    /// override the address in the identity
+    addr: Option<PeerAddr>,
+    #[argh(option)]
+    /// override the name in the identity
+    name: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "guest")]
+/// Only let a peer connect for a while, and maybe only use some devices
+struct GuestOpts {
+    #[argh(positional)]
+    /// name, host name or key fingerprint of the peer
+    peer: String,
+    #[argh(option)]
+    /// number of hours, from now on, the peer can connect for. 0 makes it a regular peer again.
+    hours: u64,
+    #[argh(option)]
+    /// name of an input device the peer can use, can be given more than once (default: all the
+    /// devices the server forwards)
+    device: Vec<String>,
+}
+
+/// Like `Config::find_peer`, but the peer also has to be one we can change
+fn find_own_peer(cfg: &Config, query: &str) -> Result<usize> {
+    let i = cfg.find_peer(query)?;
+    if let Some(origin) = cfg.peers[i].origin() {
+        return Err(anyhow!(
+            "{} is defined in {}, change it there",
+            query,
+            origin.display()
+        ));
+    }
+    Ok(i)
+}
+
+fn list(cfg: &Config) {
+    for peer in &cfg.peers {
+        let addr = peer.addr.as_ref().map(ToString::to_string);
+        print!(
+            "{:<20} {} {}",
+            peer.name.as_deref().unwrap_or("-"),
+            peer.fingerprint(),
+            addr.as_deref().unwrap_or("-")
+        );
+        if let Some(expires) = peer.expires {
+            let left = expires.saturating_sub(::config::unix_time());
+            if left == 0 {
+                print!(" (guest, expired)");
+            } else {
+                print!(" (guest, {}h{:02}m left)", left / 3600, left / 60 % 60);
+            }
+        }
+        if let Some(origin) = peer.origin() {
+            print!(" (from {})", origin.display());
+        }
+        println!();
+    }
+}
+
+fn main() -> Result<()> {
+    let PeersOpts { config, subcommand } = ::argh::from_env();
+    // None of this needs the secret key
+    let mut cfg = Config::read(&config)?;
+    use PeersSubcommands::*;
+    match subcommand {
+        List(_) => {
+            list(&cfg);
+            return Ok(());
+        }
+        Rename(opts) => {
+            let i = find_own_peer(&cfg, &opts.peer)?;
+            cfg.peers[i].name = Some(opts.name);
+        }
+        Remove(opts) => {
+            let i = find_own_peer(&cfg, &opts.peer)?;
+            let peer = cfg.peers.remove(i);
+            println!("Removed {}", peer.fingerprint());
+        }
+        SetAddr(opts) => {
+            let addr = if opts.addr == "none" {
+                None
+            } else {
+                Some(opts.addr.parse().map_err(|e| anyhow!("{}", e))?)
+            };
+            let i = find_own_peer(&cfg, &opts.peer)?;
+            cfg.peers[i].addr = addr;
+        }
+        Export(opts) => {
+            let identity = Identity {
+                public: cfg.public(),
+                addr: opts.addr,
+                name: opts.name.or_else(|| cfg.name()),
+            };
+            match opts.output {
+                Some(output) => ::std::fs::write(&output, format!("{}\n", identity))
+                    .with_context(|| format!("Failed to write {}", output.display()))?,
+                None => println!("{}", identity),
+            }
+            return Ok(());
+        }
+        Import(opts) => {
+            let identity = if Path::new(&opts.identity).is_file() {
+                ::std::fs::read_to_string(&opts.identity)
+                    .with_context(|| format!("Failed to read {}", opts.identity))?
+            } else {
+                opts.identity
+            };
+            let mut identity: Identity = identity.parse()?;
+            if identity.public == cfg.public() {
+                return Err(anyhow!("This is our own identity"));
+            }
+            if let Some(peer) = cfg.peers.iter().find(|p| p.public() == identity.public) {
+                return Err(anyhow!(
+                    "Already paired with {}",
+                    peer.name.as_deref().unwrap_or("this peer")
+                ));
+            }
+            identity.addr = opts.addr.or(identity.addr);
+            identity.name = opts.name.or(identity.name);
+            let peer = Peer::from(identity);
+            println!("Imported {}", peer.fingerprint());
+            cfg.peers.push(peer);
+        }
+        Guest(opts) => {
+            let i = find_own_peer(&cfg, &opts.peer)?;
+            let peer = &mut cfg.peers[i];
+            if opts.hours == 0 {
+                peer.expires = None;
+                peer.devices = None;
+            } else {
+                let expires = opts
+                    .hours
+                    .checked_mul(60 * 60)
+                    .and_then(|secs| ::config::unix_time().checked_add(secs))
+                    .with_context(|| format!("{} hours is too long", opts.hours))?;
+                peer.expires = Some(expires);
+                peer.devices = if opts.device.is_empty() {
+                    None
+                } else {
+                    Some(::config::DevicePolicy {
+                        allow: opts.device,
+                        deny: Vec::new(),
+                    })
+                };
+            }
+        }
+    }
+    cfg.save(&config)
+}